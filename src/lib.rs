@@ -0,0 +1,5855 @@
+//! Mirrors events from Google calendars into a CalDAV calendar.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
+    hash::{Hash, Hasher},
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use base64::Engine;
+use chrono::prelude::*;
+use chrono_tz::{OffsetComponents, Tz};
+use futures::StreamExt;
+use google_calendar3::{hyper, hyper_rustls, hyper_util, yup_oauth2, CalendarHub};
+use md5::{Digest, Md5};
+use minicaldav::{
+    self,
+    ical::{self, Ical},
+};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use regex::Regex;
+use rusqlite::OptionalExtension;
+use rustls::pki_types::pem::PemObject;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+#[derive(Clone)]
+pub struct Config {
+    pub caldav_uri: String,
+    /// CalDAV collection for each of `google_calendar_ids`, when each calendar is mirrored into a
+    /// collection of its own rather than all into `caldav_uri`.
+    pub caldav_pair_uris: Vec<String>,
+    /// Distinguishes the files kept between syncs of each calendar mirrored separately.
+    pub state_file_suffix: String,
+    pub caldav_credentials: Option<(String, String)>,
+    pub caldav_max_retries: u32,
+    pub caldav_concurrency: usize,
+    pub google_max_retries: u32,
+    pub google_calendar_ids: Vec<String>,
+    pub google_calendar_secrets_dir: String,
+    pub window_past: chrono::TimeDelta,
+    pub window_future: chrono::TimeDelta,
+    pub sync_interval: Duration,
+    pub max_consecutive_failures: u32,
+    pub metrics_addr: Option<String>,
+    pub dry_run: bool,
+    pub sync_recurrence: bool,
+    pub run_once: bool,
+    pub healthcheck: bool,
+    pub response_filter: Vec<String>,
+    pub export_diff_path: Option<PathBuf>,
+    pub summary_mode: SummaryMode,
+    pub default_tz: Tz,
+    pub show_diff_summary: bool,
+    pub google_auth_mode: GoogleAuthMode,
+    pub google_sync_tokens: bool,
+    pub state_db: Option<PathBuf>,
+    pub max_summary_length: usize,
+    pub max_description_length: usize,
+    pub summary_prefix: String,
+    pub summary_suffix: String,
+    pub caldav_proxy: Option<Url>,
+    pub google_proxy: Option<Url>,
+    pub no_proxy: Vec<String>,
+    pub http_timeout: Duration,
+    pub include_organizer: bool,
+    pub default_event_duration: chrono::TimeDelta,
+    pub caldav_ca_cert: Option<PathBuf>,
+    pub caldav_insecure_skip_verify: bool,
+    pub sync_direction: SyncDirection,
+    pub purge: bool,
+    pub assume_yes: bool,
+    pub sync_colors: bool,
+    pub include_summary_regex: Option<Regex>,
+    pub exclude_summary_regex: Option<Regex>,
+    pub caldav_create_method: CreateMethod,
+    pub window_align: WindowAlign,
+    pub google_application_secret_json: Option<String>,
+    pub google_tokens_path: PathBuf,
+    pub missed_tick_behavior: MissedTickBehavior,
+    pub min_event_duration: chrono::TimeDelta,
+    pub busy_merge: bool,
+    pub pid_file: Option<PathBuf>,
+    pub google_event_types: Vec<String>,
+    pub max_delete_fraction: f64,
+    pub max_deletes: Option<usize>,
+    pub caldav_sync_collection: bool,
+    pub serialize_tz: bool,
+    pub diff_only: bool,
+    pub user_agent: String,
+    pub sync_attendee_count: bool,
+    pub backfill_step: Option<chrono::TimeDelta>,
+    pub apply_order: ApplyOrder,
+    pub visibility_policy: VisibilityPolicy,
+    pub private_events: PrivateEvents,
+    pub organizer_only: bool,
+    pub self_email: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum GoogleAuthMode {
+    /// OAuth as an installed app, which needs a browser the first time to authorize access
+    #[default]
+    Installed,
+    /// OAuth with a code entered on another device the first time, for hosts without a browser.
+    /// The secret has to be for a "TVs and Limited Input devices" client.
+    Device,
+    /// A service account, optionally impersonating `subject` with domain-wide delegation
+    ServiceAccount {
+        key_path: PathBuf,
+        subject: Option<String>,
+    },
+}
+
+/// How much of each Google event's title is mirrored to CalDAV.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SummaryMode {
+    #[default]
+    Full,
+    /// Every event is titled "Busy"
+    Busy,
+    /// Only the first few characters of the title are kept
+    Prefix,
+}
+
+/// Which way events are synced between Google and CalDAV.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncDirection {
+    /// Google events are mirrored to CalDAV
+    #[default]
+    Pull,
+    /// Events only on CalDAV are created on Google
+    Push,
+    Both,
+}
+
+/// How new events are uploaded to the CalDAV collection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CreateMethod {
+    /// PUT to a resource named for the event's UID
+    #[default]
+    Put,
+    /// POST to the collection, which picks where to store it
+    Post,
+}
+
+/// The order in which changes are applied to the CalDAV collection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ApplyOrder {
+    /// Delete stale events, then update and create
+    #[default]
+    DeleteFirst,
+    /// Create and update events, then delete stale ones
+    CreateFirst,
+    /// Put new events over the resources of stale ones, so that old and new are never both present
+    UpdateInPlace,
+}
+
+/// Whether Google's visibility setting changes how events are mirrored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VisibilityPolicy {
+    /// Mirror private events like any other
+    #[default]
+    Show,
+    /// Hide the details of private events, or drop them, as PRIVATE_EVENTS says
+    HidePrivate,
+}
+
+/// What becomes of private events when they're hidden.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PrivateEvents {
+    /// Mirror them titled only "Private", without description, location or organizer
+    #[default]
+    Retitle,
+    /// Leave them out of the mirror
+    Drop,
+}
+
+/// How the edges of the sync window move as time passes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WindowAlign {
+    /// The window moves with every sync
+    #[default]
+    Continuous,
+    /// The window is widened out to midnight in DEFAULT_TZ, so it only moves once a day. Events
+    /// at its edges then don't come and go with every sync.
+    Day,
+}
+
+impl Config {
+    /// The configuration for syncing each Google calendar into its own CalDAV collection, or just
+    /// this one when they're all mirrored into CALDAV_URI.
+    pub fn sync_targets(&self) -> Vec<Config> {
+        if self.caldav_pair_uris.is_empty() {
+            return vec![self.clone()];
+        }
+        self.caldav_pair_uris
+            .iter()
+            .zip(&self.google_calendar_ids)
+            .map(|(caldav_uri, calendar_id)| Config {
+                caldav_uri: caldav_uri.clone(),
+                caldav_pair_uris: Vec::new(),
+                state_file_suffix: format!(
+                    "-{}",
+                    calendar_id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "_")
+                ),
+                google_calendar_ids: vec![calendar_id.clone()],
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    /// The span of time around `now` whose events are synced.
+    pub fn sync_window(&self, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let (time_min, time_max) = (now - self.window_past, now + self.window_future);
+        match self.window_align {
+            WindowAlign::Continuous => (time_min, time_max),
+            WindowAlign::Day => {
+                let midnight = |instant: DateTime<Utc>, days_later: u64| {
+                    let date = instant.with_timezone(&self.default_tz).date_naive()
+                        + chrono::Days::new(days_later);
+                    resolve_local_time(date.and_time(NaiveTime::MIN), self.default_tz)
+                        .map(|midnight| midnight.to_utc())
+                        .unwrap_or(instant)
+                };
+                (midnight(time_min, 0), midnight(time_max, 1))
+            }
+        }
+    }
+}
+
+/// Settings that may be given in the config file. Each one is an alternative to an environment
+/// variable, which takes precedence when both are set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    caldav_uri: Option<String>,
+    google_calendar_id: Option<String>,
+    window_radius_days: Option<i64>,
+    window_past_days: Option<i64>,
+    window_future_days: Option<i64>,
+    sync_interval_seconds: Option<u64>,
+    secrets_dir: Option<String>,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> anyhow::Result<ConfigFile> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        match name {
+            "CALDAV_URI" => self.caldav_uri.clone(),
+            "GOOGLE_CALENDAR_ID" => self.google_calendar_id.clone(),
+            "WINDOW_RADIUS" => self.window_radius_days.map(|days| days.to_string()),
+            "WINDOW_PAST_DAYS" => self.window_past_days.map(|days| days.to_string()),
+            "WINDOW_FUTURE_DAYS" => self.window_future_days.map(|days| days.to_string()),
+            "SYNC_INTERVAL" => self
+                .sync_interval_seconds
+                .map(|seconds| seconds.to_string()),
+            "GOOGLE_CALENDAR_SECRETS_DIR" => self.secrets_dir.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// Command-line flags that may be given instead of a configuration variable, taking precedence
+/// over both the environment and the config file.
+const CLI_FLAGS: &[(&str, &str)] = &[
+    ("--caldav-uri", "CALDAV_URI"),
+    ("--google-calendar-id", "GOOGLE_CALENDAR_ID"),
+    ("--window-radius", "WINDOW_RADIUS"),
+    ("--interval", "SYNC_INTERVAL"),
+];
+
+/// Command-line flags that take no value.
+const CLI_SWITCHES: &[&str] = &[
+    "--once",
+    "--dry-run",
+    "--diff-only",
+    "--check",
+    "--purge",
+    "--yes",
+    "--verbose",
+    "--help",
+    "-h",
+];
+
+/// The command line, parsed once by `check_args`.
+#[derive(Debug, Default, Clone)]
+pub struct Args {
+    /// Values given for CLI_FLAGS, by the variable each stands in for. The last one given wins.
+    vars: HashMap<&'static str, String>,
+    /// Config file given with --config.
+    config_file: Option<PathBuf>,
+    /// CLI_SWITCHES given.
+    switches: Vec<&'static str>,
+    /// How many times -v was given, counting -vv as two and --verbose as one.
+    verbose: usize,
+}
+
+impl Args {
+    /// Whether the switch `name`, which takes no value, was given.
+    pub fn has(&self, name: &str) -> bool {
+        self.switches.contains(&name)
+    }
+
+    /// How verbose logging should be when RUST_LOG isn't set, from the `-v` arguments or else
+    /// the value of VERBOSE.
+    pub fn verbosity(&self, verbose: Option<&str>) -> usize {
+        let from_var = match verbose.unwrap_or_default().trim() {
+            "" | "0" | "false" | "no" | "off" => 0,
+            "true" | "yes" | "on" => 1,
+            level => level.parse().unwrap_or(1),
+        };
+        self.verbose.max(from_var)
+    }
+}
+
+/// Parses `args`, checking each is a flag we know, with a value where it needs one. A misspelled
+/// `--dry-run` would otherwise be ignored, and the sync would make changes for real.
+pub fn check_args(args: &[String]) -> anyhow::Result<Args> {
+    let mut parsed = Args::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        let var = CLI_FLAGS
+            .iter()
+            .find(|(name, _)| *name == flag)
+            .map(|(_, var)| *var);
+        if var.is_some() || flag == "--config" {
+            let value = match inline_value {
+                Some(value) => Some(value),
+                None => args.next().map(String::as_str),
+            };
+            let value = match value {
+                Some(value) if !value.is_empty() && !value.starts_with("--") => value,
+                _ => anyhow::bail!("{} needs a value", flag),
+            };
+            match var {
+                Some(var) => {
+                    parsed.vars.insert(var, value.to_string());
+                }
+                None => parsed.config_file = Some(PathBuf::from(value)),
+            }
+        } else if inline_value.is_some() {
+            anyhow::bail!("Unknown argument '{}'", arg);
+        } else if let Some(switch) = CLI_SWITCHES.iter().find(|switch| **switch == flag) {
+            if *switch == "--verbose" {
+                parsed.verbose += 1;
+            }
+            parsed.switches.push(switch);
+        } else if arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|c| c == 'v') {
+            parsed.verbose += arg.len() - 1;
+        } else {
+            anyhow::bail!("Unknown argument '{}'", arg);
+        }
+    }
+    Ok(parsed)
+}
+
+/// Looks up configuration variables on the command line and in the environment, falling back to
+/// the config file.
+struct ConfigSource {
+    args: Args,
+    env: HashMap<String, String>,
+    file: ConfigFile,
+}
+
+impl ConfigSource {
+    fn var(&self, name: &str) -> Option<String> {
+        self.args
+            .vars
+            .get(name)
+            .cloned()
+            .or_else(|| self.env.get(name).cloned())
+            .or_else(|| self.file.var(name))
+    }
+
+    /// Whether the flag `name`, which takes no value, was given on the command line.
+    fn arg(&self, name: &str) -> bool {
+        self.args.has(name)
+    }
+
+    fn required(&self, name: &str) -> anyhow::Result<String> {
+        match self.var(name) {
+            Some(value) if !value.trim().is_empty() => Ok(value),
+            _ => anyhow::bail!("{} must be set", name),
+        }
+    }
+
+    fn parse<T>(&self, name: &str, default: T) -> anyhow::Result<T>
+    where
+        T: std::str::FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.var(name) {
+            Some(value) => value
+                .trim()
+                .parse()
+                .map_err(|e| anyhow::anyhow!("{} is invalid ({}), got '{}'", name, e, value)),
+            None => Ok(default),
+        }
+    }
+
+    fn flag(&self, name: &str) -> anyhow::Result<bool> {
+        match self.var(name) {
+            Some(value) => match value.trim().to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Ok(true),
+                "" | "0" | "false" | "no" | "off" => Ok(false),
+                _ => anyhow::bail!("{} must be true or false, got '{}'", name, value),
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+fn get_config_file_path(args: &Args, env: &HashMap<String, String>) -> Option<PathBuf> {
+    args.config_file
+        .clone()
+        .or_else(|| env.get("CONFIG_FILE").map(PathBuf::from))
+}
+
+fn get_window_days(
+    source: &ConfigSource,
+    name: &str,
+    default: chrono::TimeDelta,
+) -> anyhow::Result<chrono::TimeDelta> {
+    let value = match source.var(name) {
+        Some(value) => value,
+        None => return Ok(default),
+    };
+    // A zero or negative span would produce an empty or backwards window that matches nothing
+    match value.trim().parse::<i64>() {
+        Ok(days) if days > 0 => chrono::TimeDelta::try_days(days)
+            .with_context(|| format!("{} is too large, got '{}'", name, value)),
+        _ => anyhow::bail!(
+            "{} must be a positive integer number of days, got '{}'",
+            name,
+            value
+        ),
+    }
+}
+
+/// Returns how far the sync window extends into the past and the future. Each side defaults to
+/// WINDOW_RADIUS.
+fn get_sync_window(
+    source: &ConfigSource,
+) -> anyhow::Result<(chrono::TimeDelta, chrono::TimeDelta)> {
+    let radius = get_window_days(source, "WINDOW_RADIUS", chrono::TimeDelta::days(14))?;
+    Ok((
+        get_window_days(source, "WINDOW_PAST_DAYS", radius)?,
+        get_window_days(source, "WINDOW_FUTURE_DAYS", radius)?,
+    ))
+}
+
+fn get_sync_interval(source: &ConfigSource) -> anyhow::Result<Duration> {
+    match source.parse("SYNC_INTERVAL", 60)? {
+        0 => anyhow::bail!("SYNC_INTERVAL must be at least 1 second"),
+        seconds => Ok(Duration::from_secs(seconds)),
+    }
+}
+
+fn get_max_delete_fraction(source: &ConfigSource) -> anyhow::Result<f64> {
+    match source.parse("MAX_DELETE_FRACTION", 0.5)? {
+        fraction if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => anyhow::bail!("MAX_DELETE_FRACTION must be between 0 and 1"),
+    }
+}
+
+fn get_caldav_concurrency(source: &ConfigSource) -> anyhow::Result<usize> {
+    match source.parse("CALDAV_CONCURRENCY", 4)? {
+        0 => anyhow::bail!("CALDAV_CONCURRENCY must be at least 1"),
+        concurrency => Ok(concurrency),
+    }
+}
+
+/// Returns the CalDAV collections to sync with, which are either one for every Google calendar, or
+/// one for each of them in turn.
+fn get_caldav_uris(source: &ConfigSource) -> anyhow::Result<Vec<String>> {
+    let caldav_uris: Vec<String> = source
+        .required("CALDAV_URI")?
+        .split(',')
+        .map(|uri| uri.trim().to_string())
+        .filter(|uri| !uri.is_empty())
+        .collect();
+    for caldav_uri in &caldav_uris {
+        Url::parse(caldav_uri)
+            .with_context(|| format!("CALDAV_URI is not a valid URL, got '{}'", caldav_uri))?;
+    }
+    if caldav_uris.len() > 1 {
+        let calendar_ids = get_google_calendar_ids(source).unwrap_or_default();
+        if !calendar_ids.is_empty() && calendar_ids.len() != caldav_uris.len() {
+            anyhow::bail!(
+                "CALDAV_URI must list one collection, or one for each GOOGLE_CALENDAR_ID, got {} \
+                 for {} calendars",
+                caldav_uris.len(),
+                calendar_ids.len()
+            );
+        }
+    }
+    Ok(caldav_uris)
+}
+
+/// Reads the proxy URL from the first of `names` that is set.
+fn get_proxy(source: &ConfigSource, names: &[&str]) -> anyhow::Result<Option<Url>> {
+    for name in names {
+        match source.var(name) {
+            Some(proxy) if !proxy.trim().is_empty() => {
+                return Url::parse(proxy.trim())
+                    .map(Some)
+                    .with_context(|| format!("{} is not a valid URL, got '{}'", name, proxy));
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+fn get_regex(source: &ConfigSource, name: &str) -> anyhow::Result<Option<Regex>> {
+    match source.var(name) {
+        Some(pattern) => Regex::new(&pattern)
+            .map(Some)
+            .with_context(|| format!("{} is not a valid regex, got '{}'", name, pattern)),
+        None => Ok(None),
+    }
+}
+
+fn get_caldav_credentials(source: &ConfigSource) -> Option<(String, String)> {
+    let username = source.var("CALDAV_USERNAME")?;
+    let password = source.var("CALDAV_PASSWORD").unwrap_or_default();
+    Some((username, password))
+}
+
+fn get_google_calendar_ids(source: &ConfigSource) -> anyhow::Result<Vec<String>> {
+    let calendar_ids: Vec<String> = source
+        .required("GOOGLE_CALENDAR_ID")?
+        .split(',')
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .collect();
+    if calendar_ids.is_empty() {
+        anyhow::bail!("GOOGLE_CALENDAR_ID must list at least one calendar id");
+    }
+    Ok(calendar_ids)
+}
+
+const RESPONSE_STATUSES: &[&str] = &["accepted", "tentative", "needsAction", "declined"];
+
+fn get_response_filter(source: &ConfigSource) -> anyhow::Result<Vec<String>> {
+    let value = match source.var("RESPONSE_FILTER") {
+        Some(value) => value,
+        None => return Ok(vec!["accepted".to_string(), "tentative".to_string()]),
+    };
+    let statuses: Vec<String> = value
+        .split(',')
+        .map(|status| status.trim().to_string())
+        .filter(|status| !status.is_empty())
+        .collect();
+    if statuses.is_empty() {
+        anyhow::bail!("RESPONSE_FILTER must list at least one response status");
+    }
+    if let Some(status) = statuses
+        .iter()
+        .find(|status| !RESPONSE_STATUSES.contains(&status.as_str()))
+    {
+        anyhow::bail!(
+            "RESPONSE_FILTER entries must be one of {}, got '{}'",
+            RESPONSE_STATUSES.join(", "),
+            status
+        );
+    }
+    Ok(statuses)
+}
+
+const GOOGLE_EVENT_TYPES: &[&str] = &[
+    "default",
+    "outOfOffice",
+    "focusTime",
+    "workingLocation",
+    "fromGmail",
+    "birthday",
+];
+
+fn get_google_event_types(source: &ConfigSource) -> anyhow::Result<Vec<String>> {
+    let value = match source.var("GOOGLE_EVENT_TYPES") {
+        Some(value) => value,
+        None => return Ok(vec!["default".to_string()]),
+    };
+    let event_types: Vec<String> = value
+        .split(',')
+        .map(|event_type| event_type.trim().to_string())
+        .filter(|event_type| !event_type.is_empty())
+        .collect();
+    if event_types.is_empty() {
+        anyhow::bail!("GOOGLE_EVENT_TYPES must list at least one event type");
+    }
+    if let Some(event_type) = event_types
+        .iter()
+        .find(|event_type| !GOOGLE_EVENT_TYPES.contains(&event_type.as_str()))
+    {
+        anyhow::bail!(
+            "GOOGLE_EVENT_TYPES entries must be one of {}, got '{}'",
+            GOOGLE_EVENT_TYPES.join(", "),
+            event_type
+        );
+    }
+    Ok(event_types)
+}
+
+fn get_summary_mode(source: &ConfigSource) -> anyhow::Result<SummaryMode> {
+    match source.var("SUMMARY_MODE") {
+        Some(value) => match value.trim().to_lowercase().as_str() {
+            "full" => Ok(SummaryMode::Full),
+            "busy" => Ok(SummaryMode::Busy),
+            "prefix" => Ok(SummaryMode::Prefix),
+            _ => anyhow::bail!(
+                "SUMMARY_MODE must be one of full, busy, prefix, got '{}'",
+                value
+            ),
+        },
+        None => Ok(SummaryMode::Full),
+    }
+}
+
+fn get_sync_direction(source: &ConfigSource) -> anyhow::Result<SyncDirection> {
+    match source.var("SYNC_DIRECTION") {
+        Some(value) => match value.trim().to_lowercase().as_str() {
+            "pull" => Ok(SyncDirection::Pull),
+            "push" => Ok(SyncDirection::Push),
+            "both" => Ok(SyncDirection::Both),
+            _ => anyhow::bail!(
+                "SYNC_DIRECTION must be one of pull, push, both, got '{}'",
+                value
+            ),
+        },
+        None => Ok(SyncDirection::Pull),
+    }
+}
+
+fn get_create_method(source: &ConfigSource) -> anyhow::Result<CreateMethod> {
+    match source.var("CALDAV_CREATE_METHOD") {
+        Some(value) => match value.trim().to_lowercase().as_str() {
+            "put" => Ok(CreateMethod::Put),
+            "post" => Ok(CreateMethod::Post),
+            _ => anyhow::bail!(
+                "CALDAV_CREATE_METHOD must be one of put, post, got '{}'",
+                value
+            ),
+        },
+        None => Ok(CreateMethod::Put),
+    }
+}
+
+fn get_apply_order(source: &ConfigSource) -> anyhow::Result<ApplyOrder> {
+    match source.var("APPLY_ORDER") {
+        Some(value) => match value.trim().to_lowercase().as_str() {
+            "delete_first" => Ok(ApplyOrder::DeleteFirst),
+            "create_first" => Ok(ApplyOrder::CreateFirst),
+            "update_in_place" => Ok(ApplyOrder::UpdateInPlace),
+            _ => anyhow::bail!(
+                "APPLY_ORDER must be one of delete_first, create_first, update_in_place, got '{}'",
+                value
+            ),
+        },
+        None => Ok(ApplyOrder::DeleteFirst),
+    }
+}
+
+fn get_visibility_policy(source: &ConfigSource) -> anyhow::Result<VisibilityPolicy> {
+    match source.var("VISIBILITY_POLICY") {
+        Some(value) => match value.trim().to_lowercase().as_str() {
+            "show" => Ok(VisibilityPolicy::Show),
+            "hide_private" => Ok(VisibilityPolicy::HidePrivate),
+            _ => anyhow::bail!(
+                "VISIBILITY_POLICY must be one of show, hide_private, got '{}'",
+                value
+            ),
+        },
+        None => Ok(VisibilityPolicy::Show),
+    }
+}
+
+fn get_private_events(source: &ConfigSource) -> anyhow::Result<PrivateEvents> {
+    match source.var("PRIVATE_EVENTS") {
+        Some(value) => match value.trim().to_lowercase().as_str() {
+            "retitle" => Ok(PrivateEvents::Retitle),
+            "drop" => Ok(PrivateEvents::Drop),
+            _ => anyhow::bail!(
+                "PRIVATE_EVENTS must be one of retitle, drop, got '{}'",
+                value
+            ),
+        },
+        None => Ok(PrivateEvents::Retitle),
+    }
+}
+
+fn get_window_align(source: &ConfigSource) -> anyhow::Result<WindowAlign> {
+    match source.var("WINDOW_ALIGN") {
+        Some(value) => match value.trim().to_lowercase().as_str() {
+            "none" => Ok(WindowAlign::Continuous),
+            "day" => Ok(WindowAlign::Day),
+            _ => anyhow::bail!("WINDOW_ALIGN must be one of none, day, got '{}'", value),
+        },
+        None => Ok(WindowAlign::Continuous),
+    }
+}
+
+/// What happens to syncs that came due while a slow sync was still running. By default they're
+/// put off, so that syncs stay a full interval apart rather than running back to back.
+fn get_missed_tick_behavior(source: &ConfigSource) -> anyhow::Result<MissedTickBehavior> {
+    match source.var("MISSED_TICK_BEHAVIOR") {
+        Some(value) => match value.trim().to_lowercase().as_str() {
+            "burst" => Ok(MissedTickBehavior::Burst),
+            "delay" => Ok(MissedTickBehavior::Delay),
+            "skip" => Ok(MissedTickBehavior::Skip),
+            _ => anyhow::bail!(
+                "MISSED_TICK_BEHAVIOR must be one of burst, delay, skip, got '{}'",
+                value
+            ),
+        },
+        None => Ok(MissedTickBehavior::Delay),
+    }
+}
+
+fn get_google_auth_mode(source: &ConfigSource) -> anyhow::Result<GoogleAuthMode> {
+    let mode = source.var("GOOGLE_AUTH_MODE");
+    match mode
+        .as_deref()
+        .map(|mode| mode.trim().to_lowercase())
+        .as_deref()
+    {
+        None | Some("installed") => Ok(GoogleAuthMode::Installed),
+        Some("device") => Ok(GoogleAuthMode::Device),
+        Some("service_account") => {
+            let secrets_dir = source
+                .var("GOOGLE_CALENDAR_SECRETS_DIR")
+                .unwrap_or_else(|| ".".to_string());
+            Ok(GoogleAuthMode::ServiceAccount {
+                key_path: source
+                    .var("GOOGLE_SERVICE_ACCOUNT_KEY")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| Path::new(&secrets_dir).join("service_account.json")),
+                subject: source.var("GOOGLE_IMPERSONATE_USER"),
+            })
+        }
+        _ => anyhow::bail!(
+            "GOOGLE_AUTH_MODE must be installed, device or service_account, got '{}'",
+            mode.unwrap_or_default()
+        ),
+    }
+}
+
+/// Collects every configuration problem, so they can all be reported at once.
+#[derive(Default)]
+struct ConfigErrors(Vec<String>);
+
+impl ConfigErrors {
+    fn check<T: Default>(&mut self, result: anyhow::Result<T>) -> T {
+        result.unwrap_or_else(|e| {
+            self.0.push(format!("{:#}", e));
+            T::default()
+        })
+    }
+}
+
+/// Loads the configuration from the command line, as parsed by `check_args`, and environment, and
+/// the config file if one is given.
+pub fn load_config(args: Args) -> anyhow::Result<Config> {
+    // Like a variable that isn't set, one that isn't unicode has nothing we can use
+    let env = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect();
+    load_config_from(args, env)
+}
+
+/// Loads the configuration from the given environment variables, rather than the process's own.
+pub fn load_config_from(args: Args, env: HashMap<String, String>) -> anyhow::Result<Config> {
+    let file = match get_config_file_path(&args, &env) {
+        Some(path) => ConfigFile::load(&path)?,
+        None => ConfigFile::default(),
+    };
+    let source = ConfigSource { args, env, file };
+
+    let mut errors = ConfigErrors::default();
+    let sync_window = errors.check(get_sync_window(&source));
+    let secrets_dir = source
+        .var("GOOGLE_CALENDAR_SECRETS_DIR")
+        .unwrap_or_else(|| ".".to_string());
+    let caldav_uris = errors.check(get_caldav_uris(&source));
+    let mut config = Config {
+        caldav_uri: caldav_uris.first().cloned().unwrap_or_default(),
+        caldav_pair_uris: if caldav_uris.len() > 1 {
+            caldav_uris
+        } else {
+            Vec::new()
+        },
+        state_file_suffix: String::new(),
+        caldav_credentials: get_caldav_credentials(&source),
+        caldav_max_retries: errors.check(source.parse("CALDAV_MAX_RETRIES", 3)),
+        caldav_concurrency: errors.check(get_caldav_concurrency(&source)),
+        google_max_retries: errors.check(source.parse("GOOGLE_MAX_RETRIES", 3)),
+        google_calendar_ids: errors.check(get_google_calendar_ids(&source)),
+        google_calendar_secrets_dir: secrets_dir.clone(),
+        window_past: sync_window.0,
+        window_future: sync_window.1,
+        sync_interval: errors.check(get_sync_interval(&source)),
+        max_consecutive_failures: errors.check(source.parse("MAX_CONSECUTIVE_FAILURES", 10)),
+        metrics_addr: source.var("METRICS_ADDR"),
+        dry_run: errors.check(source.flag("DRY_RUN")) || source.arg("--dry-run"),
+        sync_recurrence: errors.check(source.flag("SYNC_RECURRENCE")),
+        run_once: errors.check(source.flag("RUN_ONCE")) || source.arg("--once"),
+        healthcheck: errors.check(source.flag("HEALTHCHECK")) || source.arg("--check"),
+        response_filter: errors.check(get_response_filter(&source)),
+        export_diff_path: source.var("EXPORT_DIFF_PATH").map(PathBuf::from),
+        summary_mode: errors.check(get_summary_mode(&source)),
+        // Floating times are compared with Google's by the instant they resolve to, so a zone that
+        // doesn't match the client that wrote them makes those events differ, and get rewritten
+        default_tz: errors.check(source.parse("DEFAULT_TZ", Tz::UTC)),
+        show_diff_summary: source.args.verbosity(source.var("VERBOSE").as_deref()) > 0
+            || std::io::stdout().is_terminal(),
+        google_auth_mode: errors.check(get_google_auth_mode(&source)),
+        google_sync_tokens: errors.check(source.flag("GOOGLE_SYNC_TOKENS")),
+        state_db: source.var("STATE_DB").map(PathBuf::from),
+        max_summary_length: errors.check(source.parse("MAX_SUMMARY_LENGTH", 1024)),
+        max_description_length: errors.check(source.parse("MAX_DESCRIPTION_LENGTH", 8192)),
+        summary_prefix: source.var("SUMMARY_PREFIX").unwrap_or_default(),
+        summary_suffix: source.var("SUMMARY_SUFFIX").unwrap_or_default(),
+        caldav_proxy: errors.check(get_proxy(&source, &["CALDAV_PROXY", "HTTPS_PROXY"])),
+        google_proxy: errors.check(get_proxy(&source, &["HTTPS_PROXY"])),
+        no_proxy: source
+            .var("NO_PROXY")
+            .unwrap_or_default()
+            .split(',')
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect(),
+        http_timeout: Duration::from_secs(errors.check(source.parse("HTTP_TIMEOUT_SECONDS", 30))),
+        include_organizer: errors.check(source.flag("INCLUDE_ORGANIZER")),
+        default_event_duration: chrono::TimeDelta::minutes(
+            errors.check(source.parse("DEFAULT_EVENT_DURATION_MINUTES", 60)),
+        ),
+        caldav_ca_cert: source.var("CALDAV_CA_CERT").map(PathBuf::from),
+        caldav_insecure_skip_verify: errors.check(source.flag("CALDAV_INSECURE_SKIP_VERIFY")),
+        sync_direction: errors.check(get_sync_direction(&source)),
+        purge: errors.check(source.flag("PURGE")) || source.arg("--purge"),
+        assume_yes: source.arg("--yes"),
+        sync_colors: errors.check(source.flag("SYNC_COLORS")),
+        include_summary_regex: errors.check(get_regex(&source, "INCLUDE_SUMMARY_REGEX")),
+        exclude_summary_regex: errors.check(get_regex(&source, "EXCLUDE_SUMMARY_REGEX")),
+        caldav_create_method: errors.check(get_create_method(&source)),
+        window_align: errors.check(get_window_align(&source)),
+        google_application_secret_json: source.var("GOOGLE_APPLICATION_SECRET_JSON"),
+        google_tokens_path: source
+            .var("GOOGLE_TOKENS_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(&secrets_dir).join("tokens.json")),
+        missed_tick_behavior: errors.check(get_missed_tick_behavior(&source)),
+        min_event_duration: chrono::TimeDelta::seconds(
+            errors.check(source.parse("MIN_EVENT_DURATION_SECONDS", 0)),
+        ),
+        busy_merge: errors.check(source.flag("BUSY_MERGE")),
+        pid_file: source.var("PID_FILE").map(PathBuf::from),
+        google_event_types: errors.check(get_google_event_types(&source)),
+        max_delete_fraction: errors.check(get_max_delete_fraction(&source)),
+        max_deletes: errors.check(
+            source
+                .var("MAX_DELETES")
+                .map(|_| source.parse("MAX_DELETES", 0))
+                .transpose(),
+        ),
+        caldav_sync_collection: errors.check(source.flag("CALDAV_SYNC_COLLECTION")),
+        serialize_tz: errors.check(source.flag("SERIALIZE_TZ")),
+        diff_only: errors.check(source.flag("DIFF_ONLY")) || source.arg("--diff-only"),
+        user_agent: source
+            .var("USER_AGENT")
+            .unwrap_or_else(|| format!("gcal-pull-view/{}", env!("CARGO_PKG_VERSION"))),
+        sync_attendee_count: errors.check(source.flag("SYNC_ATTENDEE_COUNT")),
+        backfill_step: errors.check(
+            source
+                .var("BACKFILL_STEP_DAYS")
+                .map(|_| get_window_days(&source, "BACKFILL_STEP_DAYS", chrono::TimeDelta::zero()))
+                .transpose(),
+        ),
+        apply_order: errors.check(get_apply_order(&source)),
+        visibility_policy: errors.check(get_visibility_policy(&source)),
+        private_events: errors.check(get_private_events(&source)),
+        organizer_only: errors.check(source.flag("ORGANIZER_ONLY")),
+        self_email: source
+            .var("SELF_EMAIL")
+            .map(|email| email.trim().to_string())
+            .filter(|email| !email.is_empty()),
+    };
+    // Occurrences are only merged once they're listed one by one, rather than as the rule making
+    // them, which would mirror each recurring event's title and details
+    if config.busy_merge {
+        config.sync_recurrence = false;
+    }
+    // Otherwise there's nowhere to record its progress, and every restart would backfill again
+    if config.backfill_step.is_some() && config.state_db.is_none() {
+        errors
+            .0
+            .push("BACKFILL_STEP_DAYS needs STATE_DB to be set".to_string());
+    }
+
+    if !errors.0.is_empty() {
+        anyhow::bail!("Invalid configuration:\n  {}", errors.0.join("\n  "));
+    }
+    Ok(config)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EventTime {
+    Timed(DateTime<Utc>),
+    AllDay(NaiveDate),
+}
+
+impl EventTime {
+    fn to_ical_property(self, name: &str) -> ical::Property {
+        self.to_ical_property_in(name, None)
+    }
+
+    /// Like `to_ical_property`, but writing timed values as local times in `tz` when there is one.
+    fn to_ical_property_in(self, name: &str, tz: Option<Tz>) -> ical::Property {
+        match self {
+            EventTime::Timed(datetime) => match tz {
+                Some(tz) => ical::Property::new_with_attributes(
+                    name,
+                    &datetime
+                        .with_timezone(&tz)
+                        .format("%Y%m%dT%H%M%S")
+                        .to_string(),
+                    vec![("TZID", tz.name())],
+                ),
+                None => ical::Property::new(name, &datetime.format("%Y%m%dT%H%M%SZ").to_string()),
+            },
+            EventTime::AllDay(date) => ical::Property::new_with_attributes(
+                name,
+                &date.format("%Y%m%d").to_string(),
+                vec![("VALUE", "DATE")],
+            ),
+        }
+    }
+
+    /// The instant this time starts at, taking all-day dates to start at midnight UTC.
+    fn start_instant(self) -> DateTime<Utc> {
+        match self {
+            EventTime::Timed(datetime) => datetime,
+            EventTime::AllDay(date) => date.and_time(NaiveTime::MIN).and_utc(),
+        }
+    }
+
+    fn to_json(self) -> serde_json::Value {
+        match self {
+            EventTime::Timed(datetime) => datetime.to_rfc3339().into(),
+            EventTime::AllDay(date) => date.to_string().into(),
+        }
+    }
+}
+
+impl fmt::Display for EventTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventTime::Timed(datetime) => datetime.fmt(f),
+            EventTime::AllDay(date) => write!(f, "{} (all day)", date),
+        }
+    }
+}
+
+const PRODID: &str = "-//gcal-pull-view//EN";
+
+/// Property stamped on every event we write, so that we never delete events added by others.
+const MANAGED_MARKER: &str = "X-GCAL-PULL-VIEW";
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    /// Id of the Google event this was mirrored from. This identifies the event across edits, so
+    /// it is deliberately not part of equality or hashing, which only compare event content.
+    pub google_id: Option<String>,
+    /// Id of the Google calendar this was mirrored from. Like `google_id`, this is not content.
+    pub calendar_id: Option<String>,
+    pub start: EventTime,
+    pub end: EventTime,
+    pub summary: String,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    /// Recurrence rule of a recurring master event, when recurrences are synced natively.
+    pub rrule: Option<String>,
+    /// Start times of cancelled occurrences of a recurring master event, in order.
+    pub exdates: Vec<EventTime>,
+    /// Start times of occurrences a recurring master event has beyond its rule, in order.
+    pub rdates: Vec<EventTime>,
+    /// Whether the event leaves its time free rather than blocking it as busy.
+    pub transparent: bool,
+    /// Email and display name of the organizer, only mirrored when INCLUDE_ORGANIZER is set.
+    pub organizer: Option<String>,
+    pub organizer_name: Option<String>,
+    /// CSS color name for the event, only mirrored when SYNC_COLORS is set.
+    pub color: Option<String>,
+    /// Zone the event's times are written in, rather than UTC. Only set when SERIALIZE_TZ is.
+    pub time_zone: Option<Tz>,
+    /// How many people were invited, only mirrored when SYNC_ATTENDEE_COUNT is set.
+    pub attendee_count: Option<usize>,
+    /// Whether the event is marked private, only mirrored when VISIBILITY_POLICY is hide_private.
+    pub private: bool,
+    /// When the Google event was created and last modified. These are bookkeeping, not content.
+    pub created: Option<DateTime<Utc>>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Self) -> bool {
+        self.start == other.start
+            && self.end == other.end
+            && self.summary == other.summary
+            && self.description == other.description
+            && self.location == other.location
+            && self.rrule == other.rrule
+            && self.exdates == other.exdates
+            && self.rdates == other.rdates
+            && self.transparent == other.transparent
+            && self.organizer == other.organizer
+            && self.organizer_name == other.organizer_name
+            && self.color == other.color
+            && self.time_zone == other.time_zone
+            && self.attendee_count == other.attendee_count
+            && self.private == other.private
+    }
+}
+
+impl Eq for Event {}
+
+impl Hash for Event {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.start.hash(state);
+        self.end.hash(state);
+        self.summary.hash(state);
+        self.description.hash(state);
+        self.location.hash(state);
+        self.rrule.hash(state);
+        self.exdates.hash(state);
+        self.rdates.hash(state);
+        self.transparent.hash(state);
+        self.organizer.hash(state);
+        self.organizer_name.hash(state);
+        self.color.hash(state);
+        self.time_zone.hash(state);
+        self.attendee_count.hash(state);
+        self.private.hash(state);
+    }
+}
+
+#[derive(Debug)]
+pub struct EventWithCaldavUid {
+    pub caldav_uid: String,
+    /// URL of the CalDAV resource holding the event. When not known, it's assumed to be named for
+    /// the event's UID, as the resources we create are.
+    pub href: Option<Url>,
+    /// ETag of the CalDAV resource when it was fetched, if the server reported one.
+    pub etag: Option<String>,
+    /// Whether this event was written by us, and so may be deleted when it has no Google match.
+    pub managed: bool,
+    pub event: Event,
+}
+
+impl Event {
+    fn to_ical(&self, uid: &str) -> Ical {
+        let mut vcalendar = Ical::new("VCALENDAR".to_string());
+        vcalendar
+            .properties
+            .push(ical::Property::new("VERSION", "2.0"));
+        vcalendar
+            .properties
+            .push(ical::Property::new("PRODID", PRODID));
+        let mut vevent = Ical::new("VEVENT".to_string());
+        vevent.properties.push(ical::Property::new("UID", uid));
+        // RFC 5545 requires DTSTAMP, and some servers refuse events without it
+        vevent
+            .properties
+            .push(EventTime::Timed(Utc::now()).to_ical_property("DTSTAMP"));
+        if let Some(created) = self.created {
+            vevent
+                .properties
+                .push(EventTime::Timed(created).to_ical_property("CREATED"));
+        }
+        if let Some(last_modified) = self.last_modified {
+            vevent
+                .properties
+                .push(EventTime::Timed(last_modified).to_ical_property("LAST-MODIFIED"));
+        }
+        vevent
+            .properties
+            .push(ical::Property::new(MANAGED_MARKER, "1"));
+        if let Some(google_id) = &self.google_id {
+            vevent
+                .properties
+                .push(ical::Property::new("X-GCAL-ID", google_id));
+        }
+        if let Some(calendar_id) = &self.calendar_id {
+            vevent
+                .properties
+                .push(ical::Property::new("X-GCAL-CALENDAR-ID", calendar_id));
+        }
+        vevent.properties.push(ical::Property::new(
+            "SUMMARY",
+            &escape_ical_text(&self.summary),
+        ));
+        if let Some(description) = &self.description {
+            vevent.properties.push(ical::Property::new(
+                "DESCRIPTION",
+                &escape_ical_text(description),
+            ));
+        }
+        if let Some(location) = &self.location {
+            vevent
+                .properties
+                .push(ical::Property::new("LOCATION", &escape_ical_text(location)));
+        }
+        if let Some(organizer) = &self.organizer {
+            let value = format!("mailto:{}", organizer);
+            // Quoted, as names often contain commas
+            let name = self
+                .organizer_name
+                .as_ref()
+                .map(|name| format!("\"{}\"", name));
+            vevent.properties.push(match &name {
+                Some(name) => {
+                    ical::Property::new_with_attributes("ORGANIZER", &value, vec![("CN", name)])
+                }
+                None => ical::Property::new("ORGANIZER", &value),
+            });
+        }
+        if let Some(color) = &self.color {
+            vevent.properties.push(ical::Property::new("COLOR", color));
+        }
+        if let Some(attendee_count) = self.attendee_count {
+            vevent.properties.push(ical::Property::new(
+                "X-ATTENDEE-COUNT",
+                &attendee_count.to_string(),
+            ));
+        }
+        if self.private {
+            vevent
+                .properties
+                .push(ical::Property::new("CLASS", "PRIVATE"));
+        }
+        vevent
+            .properties
+            .push(self.start.to_ical_property_in("DTSTART", self.time_zone));
+        vevent
+            .properties
+            .push(self.end.to_ical_property_in("DTEND", self.time_zone));
+        if let Some(rrule) = &self.rrule {
+            vevent.properties.push(ical::Property::new("RRULE", rrule));
+        }
+        for (name, times) in [("EXDATE", &self.exdates), ("RDATE", &self.rdates)] {
+            if let Some(first) = times.first() {
+                let mut property = first.to_ical_property_in(name, self.time_zone);
+                property.value = times
+                    .iter()
+                    .map(|time| time.to_ical_property_in(name, self.time_zone).value)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                vevent.properties.push(property);
+            }
+        }
+        vevent.properties.push(ical::Property::new(
+            "TRANSP",
+            if self.transparent {
+                "TRANSPARENT"
+            } else {
+                "OPAQUE"
+            },
+        ));
+        // Clients need the zone's rules to place the local times, unless they happen to know it
+        if let (Some(tz), EventTime::Timed(start)) = (self.time_zone, self.start) {
+            vcalendar.children.push(vtimezone(tz, start.year()));
+        }
+        vcalendar.children.push(vevent);
+        vcalendar
+    }
+}
+
+fn format_utc_offset(offset: FixedOffset) -> String {
+    let seconds = offset.local_minus_utc();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+/// Describes the offset changes of `tz` in `year` as a VTIMEZONE, each one recurring yearly on the
+/// same weekday of the month, which is how zones with DST define their changes.
+fn vtimezone(tz: Tz, year: i32) -> Ical {
+    const WEEKDAYS: [&str; 7] = ["MO", "TU", "WE", "TH", "FR", "SA", "SU"];
+
+    let mut vtimezone = Ical::new("VTIMEZONE".to_string());
+    vtimezone
+        .properties
+        .push(ical::Property::new("TZID", tz.name()));
+    let offset_at = |instant: DateTime<Utc>| instant.with_timezone(&tz).offset().fix();
+    let year_start = NaiveDate::from_ymd_opt(year, 1, 1)
+        .unwrap_or_default()
+        .and_time(NaiveTime::MIN)
+        .and_utc();
+
+    // Offsets change at most a few times a year, so each day is checked, and any change narrowed
+    // down to the second
+    let mut transitions = Vec::new();
+    let mut day = year_start;
+    while day.year() == year {
+        let next = day + chrono::TimeDelta::days(1);
+        if offset_at(day) != offset_at(next) {
+            let (mut before, mut after) = (day, next);
+            while after - before > chrono::TimeDelta::seconds(1) {
+                let middle = before + (after - before) / 2;
+                if offset_at(middle) == offset_at(before) {
+                    before = middle;
+                } else {
+                    after = middle;
+                }
+            }
+            transitions.push(after);
+        }
+        day = next;
+    }
+
+    let component = |name: &str, start: NaiveDateTime, from, at: DateTime<Utc>| {
+        let mut component = Ical::new(name.to_string());
+        component.properties.push(ical::Property::new(
+            "DTSTART",
+            &start.format("%Y%m%dT%H%M%S").to_string(),
+        ));
+        component.properties.push(ical::Property::new(
+            "TZOFFSETFROM",
+            &format_utc_offset(from),
+        ));
+        component.properties.push(ical::Property::new(
+            "TZOFFSETTO",
+            &format_utc_offset(offset_at(at)),
+        ));
+        component.properties.push(ical::Property::new(
+            "TZNAME",
+            &at.with_timezone(&tz).format("%Z").to_string(),
+        ));
+        component
+    };
+    if transitions.is_empty() {
+        let start = NaiveDate::from_ymd_opt(1970, 1, 1)
+            .unwrap_or_default()
+            .and_time(NaiveTime::MIN);
+        vtimezone.children.push(component(
+            "STANDARD",
+            start,
+            offset_at(year_start),
+            year_start,
+        ));
+    }
+    for at in transitions {
+        let from = offset_at(at - chrono::TimeDelta::seconds(1));
+        // Onsets are given in the local time before the change
+        let start = at.naive_utc() + chrono::TimeDelta::seconds(from.local_minus_utc().into());
+        let week = if (start.date() + chrono::Days::new(7)).month() != start.month() {
+            -1
+        } else {
+            (start.day() as i32 - 1) / 7 + 1
+        };
+        let is_dst = !at.with_timezone(&tz).offset().dst_offset().is_zero();
+        let mut component = component(
+            if is_dst { "DAYLIGHT" } else { "STANDARD" },
+            start,
+            from,
+            at,
+        );
+        component.properties.push(ical::Property::new(
+            "RRULE",
+            &format!(
+                "FREQ=YEARLY;BYMONTH={};BYDAY={}{}",
+                start.month(),
+                week,
+                WEEKDAYS[start.weekday().num_days_from_monday() as usize]
+            ),
+        ));
+        vtimezone.children.push(component);
+    }
+    vtimezone
+}
+
+fn escape_ical_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Removes the control characters that iCal text may not contain, and truncates text longer than
+/// `max_chars`. This is done as events are fetched from Google, rather than when writing them, so
+/// that the diff compares against what CalDAV will actually hold.
+fn sanitize_ical_text(text: &str, max_chars: usize) -> String {
+    let mut sanitized: String = text
+        .chars()
+        .filter(|&c| c == '\t' || c == '\n' || !c.is_control())
+        .collect();
+    if let Some((end, _)) = sanitized.char_indices().nth(max_chars) {
+        sanitized.truncate(end);
+        sanitized.pop();
+        sanitized.push('…');
+    }
+    sanitized
+}
+
+fn unescape_ical_text(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Folds serialized iCal content lines so that none exceeds 75 octets, as required by RFC 5545.
+fn fold_ical_lines(serialized: &str) -> String {
+    const MAX_LINE_OCTETS: usize = 75;
+
+    let mut folded = String::with_capacity(serialized.len());
+    for line in serialized.lines() {
+        let mut line_octets = 0;
+        for c in line.chars() {
+            if line_octets + c.len_utf8() > MAX_LINE_OCTETS {
+                folded.push_str("\r\n ");
+                // The leading space of a continuation line counts towards its length
+                line_octets = 1;
+            }
+            folded.push(c);
+            line_octets += c.len_utf8();
+        }
+        folded.push_str("\r\n");
+    }
+    folded
+}
+
+/// Parses a DATE-TIME value. Floating times, which have neither a UTC marker nor a TZID, are taken
+/// to be in `default_tz`.
+fn parse_ical_datetime(property: &ical::Property, default_tz: Tz) -> anyhow::Result<DateTime<Utc>> {
+    let str = property.value.as_str();
+    if str.ends_with('Z') {
+        Ok(NaiveDateTime::parse_from_str(property.value.as_str(), "%Y%m%dT%H%M%SZ")?.and_utc())
+    } else {
+        let tz: Tz = match property.attributes.get("TZID") {
+            Some(tzid) => tzid.parse()?,
+            None => default_tz,
+        };
+        let local = NaiveDateTime::parse_from_str(property.value.as_str(), "%Y%m%dT%H%M%S")?;
+        Ok(resolve_local_time(local, tz)?.to_utc())
+    }
+}
+
+/// Converts a wall-clock time to an instant, even across DST transitions. Ambiguous times (when
+/// clocks go back) are taken at the earlier offset, and nonexistent times (when clocks go
+/// forward) are moved to the end of the gap.
+fn resolve_local_time(local: NaiveDateTime, tz: Tz) -> anyhow::Result<DateTime<Tz>> {
+    match local.and_local_timezone(tz) {
+        chrono::LocalResult::Single(datetime) => Ok(datetime),
+        chrono::LocalResult::Ambiguous(earlier, _) => Ok(earlier),
+        chrono::LocalResult::None => {
+            // DST gaps are at most a couple of hours, and start and end on a minute boundary
+            let datetime = (1..=24 * 60)
+                .find_map(|minutes| {
+                    (local + chrono::TimeDelta::minutes(minutes))
+                        .and_local_timezone(tz)
+                        .earliest()
+                })
+                .with_context(|| format!("Invalid local time {} in {}", local, tz))?;
+            warn!(
+                "Local time {} does not exist in {}, using {} instead",
+                local, tz, datetime
+            );
+            Ok(datetime)
+        }
+    }
+}
+
+fn parse_ical_event_time(property: &ical::Property, default_tz: Tz) -> anyhow::Result<EventTime> {
+    // All-day values are marked VALUE=DATE, but some clients leave that out of a bare date
+    let is_date = property
+        .attributes
+        .get("VALUE")
+        .is_some_and(|value| value.eq_ignore_ascii_case("DATE"))
+        || property.value.trim().len() == 8;
+    if is_date {
+        let date = NaiveDate::parse_from_str(property.value.trim(), "%Y%m%d")
+            .with_context(|| format!("Invalid date {} in {}", property.value, property.name))?;
+        Ok(EventTime::AllDay(date))
+    } else {
+        Ok(EventTime::Timed(parse_ical_datetime(property, default_tz)?))
+    }
+}
+
+/// Parses every EXDATE or RDATE (as `name` says) among `properties`, each of which may list
+/// several comma-separated times.
+fn parse_ical_dates<'a>(
+    properties: impl IntoIterator<Item = &'a ical::Property>,
+    name: &str,
+    default_tz: Tz,
+) -> anyhow::Result<Vec<EventTime>> {
+    let mut times = Vec::new();
+    for property in properties.into_iter().filter(|p| p.name == name) {
+        for value in property.value.split(',') {
+            let mut single = property.clone();
+            single.value = value.trim().to_string();
+            times.push(parse_ical_event_time(&single, default_tz)?);
+        }
+    }
+    times.sort();
+    Ok(times)
+}
+
+fn get_ical_property<'a>(
+    ical: &'a Ical,
+    property_name: &str,
+) -> anyhow::Result<&'a ical::Property> {
+    ical.properties
+        .iter()
+        .find(|p| p.name == property_name)
+        .with_context(|| format!("Looking up property: {}", property_name))
+}
+
+fn describe_ical_event(event: &Ical) -> String {
+    format!(
+        "{} '{}' at {}",
+        event.name,
+        event
+            .properties
+            .iter()
+            .find(|p| p.name == "SUMMARY")
+            .map(|p| p.value.as_ref())
+            .unwrap_or("(Unknown summary)"),
+        event
+            .properties
+            .iter()
+            .find(|p| p.name == "DTSTART")
+            .map(|p| p.value.as_ref())
+            .unwrap_or("(Unknown start time)")
+    )
+}
+
+fn describe_event(event: &Event) -> String {
+    format!("'{}' at {}", event.summary, event.start)
+}
+
+fn parse_ical_duration(value: &str) -> anyhow::Result<chrono::TimeDelta> {
+    let (negative, unsigned) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let designators = unsigned
+        .strip_prefix('P')
+        .with_context(|| format!("Duration '{}' does not start with P", value))?;
+
+    let mut duration = chrono::TimeDelta::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in designators.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' if !in_time && number.is_empty() => in_time = true,
+            _ => {
+                let n: i64 = number
+                    .parse()
+                    .with_context(|| format!("Invalid duration '{}'", value))?;
+                number.clear();
+                duration += match (in_time, c) {
+                    (false, 'W') => chrono::TimeDelta::weeks(n),
+                    (false, 'D') => chrono::TimeDelta::days(n),
+                    (true, 'H') => chrono::TimeDelta::hours(n),
+                    (true, 'M') => chrono::TimeDelta::minutes(n),
+                    (true, 'S') => chrono::TimeDelta::seconds(n),
+                    _ => anyhow::bail!("Invalid duration '{}'", value),
+                };
+            }
+        }
+    }
+    if !number.is_empty() {
+        anyhow::bail!("Invalid duration '{}'", value);
+    }
+
+    Ok(if negative { -duration } else { duration })
+}
+
+/// Makes sure an event doesn't end before it starts, as malformed events (or ones in a time zone we
+/// resolve differently) can. Such events are taken to end as they start, or after a day if they're
+/// all-day, since an all-day end is exclusive.
+fn clamp_event_end(start: EventTime, end: EventTime, event_name: &str) -> EventTime {
+    if end.start_instant() >= start.start_instant() {
+        return end;
+    }
+    warn!(
+        "Event {} ends ({}) before it starts ({}), so it's taken to end as it starts",
+        event_name, end, start
+    );
+    match start {
+        EventTime::Timed(_) => start,
+        EventTime::AllDay(date) => EventTime::AllDay(date + chrono::Days::new(1)),
+    }
+}
+
+fn parse_ical_event_end(
+    ical_event: &Ical,
+    start: EventTime,
+    default_tz: Tz,
+) -> anyhow::Result<EventTime> {
+    if let Ok(dtend) = get_ical_property(ical_event, "DTEND") {
+        return parse_ical_event_time(dtend, default_tz);
+    }
+
+    // RFC 5545 allows DURATION in place of DTEND, and defines the end when both are absent
+    let duration = match get_ical_property(ical_event, "DURATION") {
+        Ok(duration) => parse_ical_duration(&duration.value)?,
+        Err(_) => match start {
+            EventTime::Timed(_) => chrono::TimeDelta::zero(),
+            EventTime::AllDay(_) => chrono::TimeDelta::days(1),
+        },
+    };
+    Ok(match start {
+        EventTime::Timed(datetime) => EventTime::Timed(datetime + duration),
+        EventTime::AllDay(date) => {
+            EventTime::AllDay(date + chrono::TimeDelta::days(duration.num_days()))
+        }
+    })
+}
+
+fn parse_caldav_event(ical_event: &Ical, default_tz: Tz) -> anyhow::Result<EventWithCaldavUid> {
+    let caldav_uid = get_ical_property(ical_event, "UID")?.value.clone();
+    let start = parse_ical_event_time(get_ical_property(ical_event, "DTSTART")?, default_tz)?;
+    let end = clamp_event_end(
+        start,
+        parse_ical_event_end(ical_event, start, default_tz)?,
+        &caldav_uid,
+    );
+    Ok(EventWithCaldavUid {
+        caldav_uid,
+        href: None,
+        etag: None,
+        // Events that record a Google id predate the marker, but were just as surely written by us
+        managed: get_ical_property(ical_event, MANAGED_MARKER).is_ok()
+            || get_ical_property(ical_event, "X-GCAL-ID").is_ok(),
+        event: Event {
+            google_id: get_ical_property(ical_event, "X-GCAL-ID")
+                .ok()
+                .map(|p| p.value.clone()),
+            calendar_id: get_ical_property(ical_event, "X-GCAL-CALENDAR-ID")
+                .ok()
+                .map(|p| p.value.clone()),
+            start,
+            end,
+            // SUMMARY is optional per RFC 5545; if it's repeated, the first one wins
+            summary: get_ical_property(ical_event, "SUMMARY")
+                .map(|p| unescape_ical_text(&p.value))
+                .unwrap_or_default(),
+            description: get_ical_property(ical_event, "DESCRIPTION")
+                .ok()
+                .map(|p| unescape_ical_text(&p.value)),
+            location: get_ical_property(ical_event, "LOCATION")
+                .ok()
+                .map(|p| unescape_ical_text(&p.value)),
+            rrule: get_ical_property(ical_event, "RRULE")
+                .ok()
+                .map(|p| p.value.clone()),
+            exdates: parse_ical_dates(&ical_event.properties, "EXDATE", default_tz)?,
+            rdates: parse_ical_dates(&ical_event.properties, "RDATE", default_tz)?,
+            transparent: get_ical_property(ical_event, "TRANSP")
+                .is_ok_and(|p| p.value.eq_ignore_ascii_case("TRANSPARENT")),
+            organizer: get_ical_property(ical_event, "ORGANIZER").ok().map(|p| {
+                let value = p.value.as_str();
+                match value.get(..7) {
+                    Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => {
+                        value[7..].to_string()
+                    }
+                    _ => value.to_string(),
+                }
+            }),
+            organizer_name: get_ical_property(ical_event, "ORGANIZER")
+                .ok()
+                .and_then(|p| p.attributes.get("CN"))
+                .map(|name| name.trim_matches('"').to_string()),
+            color: get_ical_property(ical_event, "COLOR")
+                .ok()
+                .map(|p| p.value.clone()),
+            time_zone: get_ical_property(ical_event, "DTSTART")?
+                .attributes
+                .get("TZID")
+                .and_then(|tzid| tzid.parse().ok())
+                .filter(|_| matches!(start, EventTime::Timed(_))),
+            attendee_count: get_ical_property(ical_event, "X-ATTENDEE-COUNT")
+                .ok()
+                .and_then(|p| p.value.trim().parse().ok()),
+            private: get_ical_property(ical_event, "CLASS").is_ok_and(|p| {
+                p.value.eq_ignore_ascii_case("PRIVATE")
+                    || p.value.eq_ignore_ascii_case("CONFIDENTIAL")
+            }),
+            created: get_ical_property(ical_event, "CREATED")
+                .ok()
+                .and_then(|p| parse_ical_datetime(p, default_tz).ok()),
+            last_modified: get_ical_property(ical_event, "LAST-MODIFIED")
+                .ok()
+                .and_then(|p| parse_ical_datetime(p, default_tz).ok()),
+        },
+    })
+}
+
+/// Cuts each VEVENT out of iCal data, wrapped in a calendar of its own, without parsing anything
+/// else. This lets the events of a calendar be parsed one by one when something in it is broken.
+fn split_ical_events(data: &str) -> Vec<String> {
+    let mut events = Vec::new();
+    let mut current: Option<String> = None;
+    for line in data.lines() {
+        let trimmed = line.trim_end();
+        if trimmed.eq_ignore_ascii_case("BEGIN:VEVENT") {
+            current = Some(String::new());
+        }
+        if let Some(event) = current.as_mut() {
+            event.push_str(trimmed);
+            event.push_str("\r\n");
+        }
+        if trimmed.eq_ignore_ascii_case("END:VEVENT") {
+            if let Some(event) = current.take() {
+                events.push(format!(
+                    "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{}END:VCALENDAR\r\n",
+                    event
+                ));
+            }
+        }
+    }
+    events
+}
+
+/// Parses the events in a VCALENDAR, skipping (with a warning) any that can't be understood.
+/// `source` is where the calendar came from, for the warnings.
+fn parse_caldav_calendar(
+    config: &Config,
+    data: &str,
+    source: &str,
+) -> anyhow::Result<Vec<EventWithCaldavUid>> {
+    let calendars = match minicaldav::parse_ical(data) {
+        Ok(calendar) => vec![calendar],
+        Err(e) => {
+            let events = split_ical_events(data);
+            if events.is_empty() {
+                return Err(e.into());
+            }
+            warn!(
+                "Failed to parse {} ({}), parsing its events one at a time",
+                source, e
+            );
+            events
+                .iter()
+                .filter_map(|event| match minicaldav::parse_ical(event) {
+                    Ok(calendar) => Some(calendar),
+                    Err(e) => {
+                        warn!("Skipping malformed event in {}: {}", source, e);
+                        None
+                    }
+                })
+                .collect()
+        }
+    };
+    Ok(calendars
+        .iter()
+        .flat_map(|calendar| &calendar.children)
+        .filter(|item| item.name.as_str() == "VEVENT")
+        .map(|ical_event| {
+            parse_caldav_event(ical_event, config.default_tz).with_context(|| {
+                format!(
+                    "Failed processing iCal event ({})",
+                    describe_ical_event(ical_event)
+                )
+            })
+        })
+        .filter_map(|result: anyhow::Result<EventWithCaldavUid>| {
+            if let Err(e) = &result {
+                warn!("Skipping event in {}: {:#}", source, e);
+            }
+            result.ok()
+        })
+        .collect())
+}
+
+/// Builds a calendar-query for the events overlapping `range`, or every event if there's none.
+fn calendar_query_request(range: Option<(DateTime<Utc>, DateTime<Utc>)>) -> String {
+    let time_range = match range {
+        Some((time_min, time_max)) => format!(
+            r#"<c:time-range start="{}" end="{}" />"#,
+            time_min.format("%Y%m%dT%H%M%SZ"),
+            time_max.format("%Y%m%dT%H%M%SZ")
+        ),
+        None => String::new(),
+    };
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+  <d:prop>
+    <d:getetag />
+    <c:calendar-data />
+  </d:prop>
+  <c:filter>
+    <c:comp-filter name="VCALENDAR">
+      <c:comp-filter name="VEVENT">
+        {}
+      </c:comp-filter>
+    </c:comp-filter>
+  </c:filter>
+</c:calendar-query>"#,
+        time_range
+    )
+}
+
+/// Fetches the events in the sync window from a calendar collection, with a calendar-query REPORT.
+pub async fn fetch_caldav_events(
+    config: &Config,
+    agent: &ureq::Agent,
+    collection_url: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Vec<EventWithCaldavUid>> {
+    query_caldav_events(config, agent, collection_url, Some(config.sync_window(now))).await
+}
+
+async fn query_caldav_events(
+    config: &Config,
+    agent: &ureq::Agent,
+    collection_url: &str,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> anyhow::Result<Vec<EventWithCaldavUid>> {
+    let collection = Url::parse(collection_url)
+        .with_context(|| format!("Invalid collection URL {}", collection_url))?;
+    let request = agent
+        .request("REPORT", collection.as_str())
+        .set("Content-Type", "application/xml; charset=utf-8")
+        .set("Depth", "1");
+    let body = calendar_query_request(range);
+    let response = with_retry(config, request, Some(&body))
+        .await
+        .with_context(|| format!("REPORT {} failed", collection))?;
+    let multistatus = xmltree::Element::parse(response.into_reader())
+        .with_context(|| format!("Failed to parse REPORT response from {}", collection))?;
+
+    let mut events = Vec::new();
+    for response in multistatus
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .filter(|element| element.name == "response")
+    {
+        let href = find_dav_href(response, &collection);
+        let data = match find_dav_prop(response, "calendar-data").and_then(|prop| prop.get_text()) {
+            Some(data) => data,
+            None => continue,
+        };
+        let etag = find_dav_prop(response, "getetag")
+            .and_then(|prop| prop.get_text())
+            .map(|etag| etag.trim().to_string());
+
+        let source = href.as_ref().unwrap_or(&collection).as_str();
+        let resource_events = match parse_caldav_calendar(config, data.trim(), source) {
+            Ok(resource_events) => resource_events,
+            Err(e) => {
+                warn!("Skipping CalDAV resource {}: {:#}", source, e);
+                continue;
+            }
+        };
+        events.extend(resource_events.into_iter().map(|mut event| {
+            event.href = href.clone();
+            event.etag = etag.clone();
+            event
+        }));
+    }
+    Ok(events)
+}
+
+/// A copy of every resource in a CalDAV collection, as of the sync token it was fetched with. This
+/// lets each sync download only the resources that changed since.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct CaldavSyncCache {
+    collection_url: String,
+    sync_token: String,
+    /// Calendar data and ETag of each resource, by URL
+    resources: HashMap<String, CachedResource>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedResource {
+    etag: Option<String>,
+    data: String,
+}
+
+fn caldav_sync_path(config: &Config) -> PathBuf {
+    Path::new(&config.google_calendar_secrets_dir)
+        .join(format!("caldav_sync{}.json", config.state_file_suffix))
+}
+
+fn load_caldav_sync_cache(path: &Path) -> anyhow::Result<Option<CaldavSyncCache>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    serde_json::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn save_caldav_sync_cache(path: &Path, cache: &CaldavSyncCache) -> anyhow::Result<()> {
+    std::fs::write(path, serde_json::to_string(cache)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn sync_collection_request(sync_token: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<d:sync-collection xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+  <d:sync-token>{}</d:sync-token>
+  <d:sync-level>1</d:sync-level>
+  <d:prop>
+    <d:getetag />
+    <c:calendar-data />
+  </d:prop>
+</d:sync-collection>"#,
+        sync_token
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    )
+}
+
+/// Applies the changes listed by a sync-collection REPORT to `cache`, returning the new sync token.
+fn apply_sync_collection(
+    cache: &mut CaldavSyncCache,
+    multistatus: &xmltree::Element,
+    collection: &Url,
+) -> anyhow::Result<String> {
+    for response in multistatus
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .filter(|element| element.name == "response")
+    {
+        let Some(href) = find_dav_href(response, collection) else {
+            continue;
+        };
+        // The collection itself may be listed, without any calendar data
+        if href == *collection {
+            continue;
+        }
+        // Removed resources have a status of their own, rather than properties
+        let removed = response
+            .get_child("status")
+            .and_then(|status| status.get_text())
+            .is_some_and(|status| status.contains(" 404"));
+        if removed {
+            cache.resources.remove(href.as_str());
+            continue;
+        }
+        let data = find_dav_prop(response, "calendar-data")
+            .and_then(|prop| prop.get_text())
+            .with_context(|| format!("No calendar data returned for {}", href))?;
+        let etag = find_dav_prop(response, "getetag")
+            .and_then(|prop| prop.get_text())
+            .map(|etag| etag.trim().to_string());
+        cache.resources.insert(
+            href.to_string(),
+            CachedResource {
+                etag,
+                data: data.trim().to_string(),
+            },
+        );
+    }
+    multistatus
+        .get_child("sync-token")
+        .and_then(|token| token.get_text())
+        .map(|token| token.trim().to_string())
+        .with_context(|| "No sync token returned")
+}
+
+/// Fetches every event in a calendar collection, downloading only what changed since the last
+/// time with a sync-collection REPORT (RFC 6578). Changes are merged into a copy of the collection
+/// kept next to the Google secrets, which is thrown away if the server no longer accepts its token.
+async fn fetch_caldav_changes(
+    config: &Config,
+    agent: &ureq::Agent,
+    collection_url: &str,
+) -> anyhow::Result<Vec<EventWithCaldavUid>> {
+    let collection = Url::parse(collection_url)
+        .with_context(|| format!("Invalid collection URL {}", collection_url))?;
+    let path = caldav_sync_path(config);
+    let mut cache = match load_caldav_sync_cache(&path) {
+        Ok(Some(cache)) if cache.collection_url == collection_url => cache,
+        Ok(_) => CaldavSyncCache::default(),
+        Err(e) => {
+            warn!("Ignoring CalDAV sync cache: {:#}", e);
+            CaldavSyncCache::default()
+        }
+    };
+    cache.collection_url = collection_url.to_string();
+
+    let request = agent
+        .request("REPORT", collection.as_str())
+        .set("Content-Type", "application/xml; charset=utf-8")
+        .set("Depth", "0");
+    let response = match with_retry(
+        config,
+        request.clone(),
+        Some(&sync_collection_request(&cache.sync_token)),
+    )
+    .await
+    {
+        Err(e)
+            if !cache.sync_token.is_empty()
+                && matches!(*e, ureq::Error::Status(403 | 409 | 410, _)) =>
+        {
+            info!("CalDAV sync token is no longer valid, fetching the whole collection again");
+            cache = CaldavSyncCache {
+                collection_url: collection_url.to_string(),
+                ..CaldavSyncCache::default()
+            };
+            with_retry(config, request, Some(&sync_collection_request("")))
+                .await
+                .with_context(|| format!("sync-collection REPORT {} failed", collection))?
+        }
+        result => {
+            result.with_context(|| format!("sync-collection REPORT {} failed", collection))?
+        }
+    };
+    let multistatus = xmltree::Element::parse(response.into_reader())
+        .with_context(|| format!("Failed to parse REPORT response from {}", collection))?;
+    let changes = multistatus
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .filter(|element| element.name == "response")
+        .count();
+    cache.sync_token = apply_sync_collection(&mut cache, &multistatus, &collection)?;
+    debug!(
+        "{} CalDAV resources changed, {} cached",
+        changes,
+        cache.resources.len()
+    );
+    if let Err(e) = save_caldav_sync_cache(&path, &cache) {
+        warn!("Unable to save CalDAV sync cache: {:#}", e);
+    }
+
+    let mut events = Vec::new();
+    for (href, resource) in &cache.resources {
+        let resource_events = match parse_caldav_calendar(config, &resource.data, href) {
+            Ok(resource_events) => resource_events,
+            Err(e) => {
+                warn!("Skipping CalDAV resource {}: {:#}", href, e);
+                continue;
+            }
+        };
+        let href = Url::parse(href).ok();
+        events.extend(resource_events.into_iter().map(|mut event| {
+            event.href = href.clone();
+            event.etag = resource.etag.clone();
+            event
+        }));
+    }
+    Ok(events)
+}
+
+/// Fetches every event at once from a URL serving the whole calendar as a single iCal file.
+async fn fetch_caldav_export(
+    config: &Config,
+    agent: &ureq::Agent,
+    caldav_url: &str,
+) -> anyhow::Result<Vec<EventWithCaldavUid>> {
+    let data = with_retry(config, agent.get(caldav_url), None)
+        .await?
+        .into_string()?;
+    parse_caldav_calendar(config, &data, caldav_url)
+}
+
+/// The most attendees listed for each event when counting them. Google lists only our own entry
+/// for larger events, setting attendeesOmitted, so those aren't given a count.
+const MAX_COUNTED_ATTENDEES: i32 = 1000;
+
+fn is_not_accepted(
+    google_event: &google_calendar3::api::Event,
+    response_filter: &[String],
+) -> bool {
+    // Others' responses don't matter, when they're listed
+    let attendees = google_event.attendees.iter().flatten();
+    let has_self = attendees
+        .clone()
+        .any(|attendee| attendee.self_ == Some(true));
+    attendees
+        .filter(|attendee| !has_self || attendee.self_ == Some(true))
+        .any(|attendee| match attendee.response_status.as_ref() {
+            Some(status) => !response_filter.contains(status),
+            None => false,
+        })
+}
+
+/// Whether we organized `google_event`, as Google marks it or as told by SELF_EMAIL, which is needed
+/// when the calendar isn't the authorized user's own.
+fn is_organized_by_self(
+    google_event: &google_calendar3::api::Event,
+    self_email: Option<&str>,
+) -> bool {
+    google_event.organizer.as_ref().is_some_and(|organizer| {
+        organizer.self_ == Some(true)
+            || organizer
+                .email
+                .as_deref()
+                .zip(self_email)
+                .is_some_and(|(email, self_email)| email.eq_ignore_ascii_case(self_email))
+    })
+}
+
+fn google_event_time(time: &google_calendar3::api::EventDateTime) -> Option<EventTime> {
+    match (time.date_time, time.date) {
+        (Some(datetime), _) => Some(EventTime::Timed(datetime)),
+        (None, Some(date)) => Some(EventTime::AllDay(date)),
+        (None, None) => None,
+    }
+}
+
+fn google_event_rrule(google_event: &google_calendar3::api::Event) -> Option<String> {
+    google_event
+        .recurrence
+        .iter()
+        .flatten()
+        .find_map(|line| line.strip_prefix("RRULE:"))
+        .map(|rrule| rrule.to_string())
+}
+
+/// The EXDATE or RDATE (as `name` says) times among the lines of a Google event's recurrence.
+/// Those without a TZID are taken to be in the event's own zone, or else UTC.
+fn google_event_recurrence_dates(
+    google_event: &google_calendar3::api::Event,
+    name: &str,
+) -> Vec<EventTime> {
+    let default_tz = google_event
+        .start
+        .as_ref()
+        .and_then(|start| start.time_zone.as_deref())
+        .and_then(|time_zone| time_zone.parse().ok())
+        .unwrap_or(Tz::UTC);
+    let properties: Vec<ical::Property> = google_event
+        .recurrence
+        .iter()
+        .flatten()
+        .filter_map(|line| ical::Property::parse(line).ok())
+        .collect();
+    parse_ical_dates(&properties, name, default_tz).unwrap_or_else(|e| {
+        warn!(
+            "Ignoring the {} lines of Google event {:?}: {:#}",
+            name, google_event.id, e
+        );
+        Vec::new()
+    })
+}
+
+/// The CSS color name (as RFC 7986 COLOR wants) closest to each of Google's event colors.
+const GOOGLE_EVENT_COLORS: [(&str, &str); 11] = [
+    ("1", "mediumpurple"),   // Lavender
+    ("2", "mediumseagreen"), // Sage
+    ("3", "darkorchid"),     // Grape
+    ("4", "salmon"),         // Flamingo
+    ("5", "gold"),           // Banana
+    ("6", "orangered"),      // Tangerine
+    ("7", "deepskyblue"),    // Peacock
+    ("8", "dimgray"),        // Graphite
+    ("9", "royalblue"),      // Blueberry
+    ("10", "seagreen"),      // Basil
+    ("11", "crimson"),       // Tomato
+];
+
+fn google_color_name(color_id: &str) -> Option<&'static str> {
+    GOOGLE_EVENT_COLORS
+        .iter()
+        .find(|(id, _)| *id == color_id)
+        .map(|(_, name)| *name)
+}
+
+const PASSIVE_EVENTS: [&str; 4] = ["Color Coordinated", "All Hands", "Async Eng", "TCIF"];
+
+/// Title for events of the special types, which Google may list without a summary.
+fn google_event_type_title(google_event: &google_calendar3::api::Event) -> Option<&'static str> {
+    match google_event.event_type.as_deref()? {
+        "outOfOffice" => Some("Out of office"),
+        "focusTime" => Some("Focus time"),
+        "workingLocation" => Some("Working location"),
+        _ => None,
+    }
+}
+
+fn is_passive_event(google_event: &google_calendar3::api::Event) -> bool {
+    let summary = match google_event.summary.as_ref() {
+        Some(summary) => summary,
+        None => return false,
+    };
+
+    PASSIVE_EVENTS.iter().any(|event| summary.contains(event))
+}
+
+fn is_google_rate_limit(status: u16, error: Option<&serde_json::Value>) -> bool {
+    match status {
+        429 => true,
+        // Google reports quota exhaustion as a 403, distinguished from permission errors by reason
+        403 => error
+            .and_then(|error| error["error"]["errors"].as_array())
+            .is_some_and(|errors| {
+                errors.iter().any(|e| {
+                    matches!(
+                        e["reason"].as_str(),
+                        Some("rateLimitExceeded") | Some("userRateLimitExceeded")
+                    )
+                })
+            }),
+        _ => false,
+    }
+}
+
+/// When the latest attempt at a Google request started, or will once the wait before a retry is
+/// over. Each attempt gets the whole of HTTP_TIMEOUT_SECONDS from then.
+type AttemptStart = Arc<Mutex<tokio::time::Instant>>;
+
+/// Retries Google API requests that were rate limited, honoring any Retry-After.
+struct GoogleRateLimitDelegate {
+    max_retries: u32,
+    attempt: u32,
+    attempt_started: AttemptStart,
+}
+
+impl GoogleRateLimitDelegate {
+    fn new(config: &Config) -> GoogleRateLimitDelegate {
+        GoogleRateLimitDelegate {
+            max_retries: config.google_max_retries,
+            attempt: 0,
+            attempt_started: Arc::new(Mutex::new(tokio::time::Instant::now())),
+        }
+    }
+}
+
+impl google_calendar3::Delegate for GoogleRateLimitDelegate {
+    fn http_failure(
+        &mut self,
+        response: &google_calendar3::common::Response,
+        error: Option<&serde_json::Value>,
+    ) -> google_calendar3::common::Retry {
+        if self.attempt >= self.max_retries
+            || !is_google_rate_limit(response.status().as_u16(), error)
+        {
+            return google_calendar3::common::Retry::Abort;
+        }
+
+        let backoff = response
+            .headers()
+            .get("Retry-After")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| retry_backoff(self.attempt));
+        self.attempt += 1;
+        *self.attempt_started.lock().unwrap() = tokio::time::Instant::now() + backoff;
+        warn!(
+            "Google API rate limit exceeded, retrying in {:?} ({}/{})",
+            backoff, self.attempt, self.max_retries
+        );
+        google_calendar3::common::Retry::After(backoff)
+    }
+}
+
+const SUMMARY_PREFIX_CHARS: usize = 4;
+
+fn mirrored_summary(mode: SummaryMode, summary: &str) -> String {
+    match mode {
+        SummaryMode::Full => summary.to_string(),
+        SummaryMode::Busy => "Busy".to_string(),
+        SummaryMode::Prefix => {
+            let mut chars = summary.chars();
+            let prefix: String = chars.by_ref().take(SUMMARY_PREFIX_CHARS).collect();
+            if chars.next().is_some() {
+                format!("{}…", prefix)
+            } else {
+                prefix
+            }
+        }
+    }
+}
+
+/// Whether `host` matches an entry in NO_PROXY, which may be `*`, a host, or a domain suffix.
+fn is_no_proxy(no_proxy: &[String], host: &str) -> bool {
+    let host = host.to_lowercase();
+    no_proxy.iter().any(|entry| {
+        let entry = entry.split(':').next().unwrap_or_default();
+        let domain = entry.trim_start_matches('.');
+        entry == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+    })
+}
+
+/// Connects to Google directly, or through an HTTP proxy tunnel (`CONNECT`) if one is configured.
+#[derive(Clone)]
+pub struct GoogleConnector {
+    http: hyper_util::client::legacy::connect::HttpConnector,
+    proxy: Option<Url>,
+    no_proxy: Vec<String>,
+}
+
+type ConnectorError = Box<dyn std::error::Error + Send + Sync>;
+
+impl tower_service::Service<hyper::Uri> for GoogleConnector {
+    type Response = hyper_util::rt::TokioIo<tokio::net::TcpStream>;
+    type Error = ConnectorError;
+    type Future =
+        std::pin::Pin<Box<dyn Future<Output = Result<Self::Response, ConnectorError>> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), ConnectorError>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: hyper::Uri) -> Self::Future {
+        let mut http = self.http.clone();
+        let host = uri.host().unwrap_or_default().to_string();
+        let proxy = self
+            .proxy
+            .clone()
+            .filter(|_| !is_no_proxy(&self.no_proxy, &host));
+        Box::pin(async move {
+            let Some(proxy) = proxy else {
+                return Ok(http.call(uri).await?);
+            };
+            let port = uri.port_u16().unwrap_or(443);
+            let proxy_uri: hyper::Uri = format!(
+                "http://{}:{}",
+                proxy.host_str().unwrap_or_default(),
+                proxy.port_or_known_default().unwrap_or(8080)
+            )
+            .parse()?;
+            let mut stream = http.call(proxy_uri).await?.into_inner();
+
+            let mut request = format!(
+                "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+                host = host,
+                port = port
+            );
+            if !proxy.username().is_empty() {
+                let credentials = format!(
+                    "{}:{}",
+                    proxy.username(),
+                    proxy.password().unwrap_or_default()
+                );
+                request.push_str(&format!(
+                    "Proxy-Authorization: Basic {}\r\n",
+                    base64::engine::general_purpose::STANDARD.encode(credentials)
+                ));
+            }
+            request.push_str("\r\n");
+            stream.write_all(request.as_bytes()).await?;
+
+            // Read byte by byte, so nothing past the proxy's response is taken from the tunnel
+            let mut response = Vec::new();
+            while !response.ends_with(b"\r\n\r\n") {
+                if response.len() > 8192 {
+                    return Err("Proxy response headers too long".into());
+                }
+                let mut byte = [0];
+                if stream.read(&mut byte).await? == 0 {
+                    return Err("Proxy closed the connection".into());
+                }
+                response.push(byte[0]);
+            }
+            let response = String::from_utf8_lossy(&response);
+            let status_line = response.lines().next().unwrap_or_default();
+            if status_line.split_whitespace().nth(1) != Some("200") {
+                return Err(format!("Proxy refused CONNECT to {}: {}", host, status_line).into());
+            }
+            Ok(hyper_util::rt::TokioIo::new(stream))
+        })
+    }
+}
+
+pub type GoogleHub = CalendarHub<hyper_rustls::HttpsConnector<GoogleConnector>>;
+
+/// The OAuth client secret, from GOOGLE_APPLICATION_SECRET_JSON or secret.json.
+async fn google_application_secret(
+    config: &Config,
+) -> anyhow::Result<yup_oauth2::ApplicationSecret> {
+    // Injecting the secret through the environment spares mounting it as a file
+    match &config.google_application_secret_json {
+        Some(json) => yup_oauth2::parse_application_secret(json)
+            .with_context(|| "Failed to parse GOOGLE_APPLICATION_SECRET_JSON"),
+        None => {
+            let path = Path::new(&config.google_calendar_secrets_dir).join("secret.json");
+            yup_oauth2::read_application_secret(&path)
+                .await
+                .with_context(|| format!("Failed to read {}", path.display()))
+        }
+    }
+}
+
+/// Builds the authenticated Google client. This reads the secrets and sets up the authenticator,
+/// which then caches its tokens, so it's meant to be built once and shared by every sync.
+pub async fn google_hub(config: &Config) -> anyhow::Result<GoogleHub> {
+    let mut http = hyper_util::client::legacy::connect::HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(config.http_timeout));
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .unwrap()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(GoogleConnector {
+            http,
+            proxy: config.google_proxy.clone(),
+            no_proxy: config.no_proxy.clone(),
+        });
+    let client = hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
+        .build(connector.clone());
+    // The authenticator has a client of its own, which has to go through the proxy too
+    let auth_client =
+        hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
+            .build::<_, String>(connector);
+
+    let auth = match &config.google_auth_mode {
+        GoogleAuthMode::Installed => {
+            let secret = google_application_secret(config).await?;
+            yup_oauth2::InstalledFlowAuthenticator::with_client(
+                secret,
+                yup_oauth2::InstalledFlowReturnMethod::HTTPRedirect,
+                auth_client,
+            )
+            .persist_tokens_to_disk(&config.google_tokens_path)
+            .build()
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to set up Google authentication with {}",
+                    config.google_tokens_path.display()
+                )
+            })?
+        }
+        GoogleAuthMode::Device => {
+            // Prints the URL to visit and the code to enter there, then waits for it to be entered
+            let secret = google_application_secret(config).await?;
+            yup_oauth2::DeviceFlowAuthenticator::with_client(secret, auth_client)
+                .persist_tokens_to_disk(&config.google_tokens_path)
+                .build()
+                .await
+                .with_context(|| "Failed to create device flow authenticator")?
+        }
+        GoogleAuthMode::ServiceAccount { key_path, subject } => {
+            let key = yup_oauth2::read_service_account_key(key_path)
+                .await
+                .with_context(|| {
+                    format!("Failed to read service account key {}", key_path.display())
+                })?;
+            let mut builder =
+                yup_oauth2::ServiceAccountAuthenticator::with_client(key, auth_client);
+            if let Some(subject) = subject {
+                builder = builder.subject(subject);
+            }
+            builder
+                .build()
+                .await
+                .with_context(|| "Failed to create service account authenticator")?
+        }
+    };
+    let mut hub = CalendarHub::new(client, auth);
+    hub.user_agent(config.user_agent.clone());
+    Ok(hub)
+}
+
+/// Runs a Google request, giving up if an attempt at it hasn't finished within
+/// HTTP_TIMEOUT_SECONDS. Waits before retries, as kept track of in `attempt_started`, don't count.
+async fn with_google_timeout<T>(
+    config: &Config,
+    attempt_started: Option<&AttemptStart>,
+    request: impl Future<Output = google_calendar3::Result<T>>,
+) -> anyhow::Result<T> {
+    let started = tokio::time::Instant::now();
+    let deadline = || match attempt_started {
+        Some(attempt_started) => *attempt_started.lock().unwrap() + config.http_timeout,
+        None => started + config.http_timeout,
+    };
+    tokio::pin!(request);
+    loop {
+        tokio::select! {
+            result = &mut request => return Ok(result?),
+            _ = tokio::time::sleep_until(deadline()) => {
+                // Unless a retry has started since, with its own time to finish
+                if deadline() <= tokio::time::Instant::now() {
+                    anyhow::bail!("Google request timed out after {:?}", config.http_timeout);
+                }
+            }
+        }
+    }
+}
+
+/// Fetches the events to mirror from every configured Google calendar.
+pub async fn fetch_google_events(config: &Config, hub: &GoogleHub) -> anyhow::Result<Vec<Event>> {
+    let mut google_events = Vec::new();
+    for calendar_id in &config.google_calendar_ids {
+        google_events.extend(
+            fetch_google_calendar(config, hub, calendar_id)
+                .await
+                .with_context(|| format!("Failed to fetch Google calendar {}", calendar_id))?,
+        );
+    }
+    if config.busy_merge {
+        google_events = merge_busy_events(config, google_events);
+    }
+    Ok(google_events)
+}
+
+/// Replaces events with the fewest "Busy" blocks covering the same time, merging any that overlap
+/// or touch. Free events are dropped. Recurring masters, whose occurrences aren't known here, are
+/// kept as busy blocks of their own. The blocks have no Google id, so they're matched by content.
+fn merge_busy_events(config: &Config, events: Vec<Event>) -> Vec<Event> {
+    let busy_block = |start, end| Event {
+        google_id: None,
+        calendar_id: None,
+        start,
+        end,
+        summary: format!("{}Busy{}", config.summary_prefix, config.summary_suffix),
+        description: None,
+        location: None,
+        rrule: None,
+        exdates: Vec::new(),
+        rdates: Vec::new(),
+        transparent: false,
+        organizer: None,
+        organizer_name: None,
+        color: None,
+        time_zone: None,
+        attendee_count: None,
+        private: false,
+        created: None,
+        last_modified: None,
+    };
+    let (recurring, mut busy): (Vec<Event>, Vec<Event>) = events
+        .into_iter()
+        .filter(|event| !event.transparent)
+        .partition(|event| event.rrule.is_some());
+    // All-day events sort after timed ones, so each kind is merged only with its own
+    busy.sort_by_key(|event| (event.start, event.end));
+
+    let mut merged: Vec<Event> = Vec::new();
+    for event in busy {
+        if let Some(last) = merged.last_mut() {
+            let same_kind = matches!(
+                (last.end, event.start),
+                (EventTime::Timed(_), EventTime::Timed(_))
+                    | (EventTime::AllDay(_), EventTime::AllDay(_))
+            );
+            if same_kind && event.start <= last.end {
+                last.end = last.end.max(event.end);
+                continue;
+            }
+        }
+        merged.push(busy_block(event.start, event.end));
+    }
+    merged.extend(recurring.into_iter().map(|event| Event {
+        rrule: event.rrule,
+        exdates: event.exdates,
+        rdates: event.rdates,
+        ..busy_block(event.start, event.end)
+    }));
+    merged
+}
+
+async fn fetch_google_calendar(
+    config: &Config,
+    hub: &GoogleHub,
+    calendar_id: &str,
+) -> anyhow::Result<Vec<Event>> {
+    let now = chrono::Utc::now();
+    let (time_min, time_max) = config.sync_window(now);
+
+    let sync_recurrence = config.sync_recurrence;
+
+    let mut google_events = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut request = hub
+            .events()
+            .list(calendar_id)
+            .max_results(2500)
+            .single_events(!sync_recurrence)
+            // Beyond this many attendees, only our own response is listed, which is all that's
+            // needed unless they're being counted
+            .max_attendees(if config.sync_attendee_count {
+                MAX_COUNTED_ATTENDEES
+            } else {
+                1
+            })
+            .time_min(time_min)
+            .time_max(time_max);
+        for event_type in &config.google_event_types {
+            request = request.add_event_types(event_type);
+        }
+        // Google only supports ordering by start time when recurrences are expanded
+        if !sync_recurrence {
+            request = request.order_by("startTime");
+        }
+        if let Some(token) = &page_token {
+            request = request.page_token(token);
+        }
+        let mut delegate = GoogleRateLimitDelegate::new(config);
+        let attempt_started = delegate.attempt_started.clone();
+        let result = with_google_timeout(
+            config,
+            Some(&attempt_started),
+            request.delegate(&mut delegate).doit(),
+        )
+        .await?
+        .1;
+
+        google_events.extend(
+            result
+                .items
+                .with_context(|| "Calendar events should exist")?,
+        );
+        page_token = result.next_page_token.filter(|token| !token.is_empty());
+        if page_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(mirror_google_events(config, calendar_id, &google_events))
+}
+
+/// The events to mirror from those listed in the Google calendar `calendar_id`.
+fn mirror_google_events(
+    config: &Config,
+    calendar_id: &str,
+    google_events: &[google_calendar3::api::Event],
+) -> Vec<Event> {
+    let sync_recurrence = config.sync_recurrence;
+    let summary_mode = config.summary_mode;
+
+    // Cancelled and modified occurrences of a recurring event are listed separately from their
+    // master, and become its EXDATEs. Modified ones are then mirrored as events of their own.
+    let mut exdates: HashMap<&str, Vec<EventTime>> = HashMap::new();
+    if sync_recurrence {
+        for google_event in google_events {
+            let master_id = google_event.recurring_event_id.as_deref();
+            let time = google_event
+                .original_start_time
+                .as_ref()
+                .and_then(google_event_time);
+            if let (Some(master_id), Some(time)) = (master_id, time) {
+                exdates.entry(master_id).or_default().push(time);
+            }
+        }
+        exdates.values_mut().for_each(|times| times.sort());
+    }
+
+    google_events
+        .iter()
+        .filter_map(|google_event| {
+            // Dropping cancelled events lets find_diff delete any copy mirrored before
+            if google_event.status.as_deref() == Some("cancelled") {
+                return None;
+            }
+
+            if is_not_accepted(google_event, &config.response_filter) {
+                return None;
+            }
+
+            if config.organizer_only
+                && !is_organized_by_self(google_event, config.self_email.as_deref())
+            {
+                return None;
+            }
+
+            if is_passive_event(google_event) {
+                return None;
+            }
+
+            let title = google_event
+                .summary
+                .as_deref()
+                .or_else(|| google_event_type_title(google_event));
+            // Matched against the Google title, since the mirrored one may be hidden or trimmed
+            if let Some(include) = &config.include_summary_regex {
+                if !include.is_match(title.unwrap_or_default()) {
+                    return None;
+                }
+            }
+            if let Some(exclude) = &config.exclude_summary_regex {
+                if exclude.is_match(title.unwrap_or_default()) {
+                    return None;
+                }
+            }
+
+            let skip = |reason: &str| {
+                debug!(
+                    "Skipping Google event {} in calendar {}: {}",
+                    google_event.id.as_deref().unwrap_or("(no id)"),
+                    calendar_id,
+                    reason
+                );
+                None
+            };
+            // Google still lists "confidential", which it now treats the same as private
+            let private = config.visibility_policy == VisibilityPolicy::HidePrivate
+                && matches!(
+                    google_event.visibility.as_deref(),
+                    Some("private" | "confidential")
+                );
+            if private && config.private_events == PrivateEvents::Drop {
+                return skip("it is private");
+            }
+            let Some(google_summary) = (if private { Some("Private") } else { title }) else {
+                return skip("it has no summary");
+            };
+
+            if google_summary.contains("Product Design") {
+                debug!("{:#?}", google_event);
+            }
+
+            // The summary is what's compared when diffing, so it has to be transformed (and
+            // tagged) here rather than when writing, or every event would look changed on each
+            // sync. Descriptions, locations and organizers are as revealing as titles, so they're
+            // only kept in full mode.
+            let full = summary_mode == SummaryMode::Full && !private;
+            let Some(start) = google_event.start.as_ref().and_then(google_event_time) else {
+                return skip("it has no start");
+            };
+            let end = match google_event.end.as_ref().and_then(google_event_time) {
+                Some(end) => end,
+                None => {
+                    debug!(
+                        "Google event {:?} has no end, assuming the default duration",
+                        google_event.id
+                    );
+                    match start {
+                        EventTime::Timed(datetime) => {
+                            EventTime::Timed(datetime + config.default_event_duration)
+                        }
+                        EventTime::AllDay(date) => EventTime::AllDay(date + chrono::Days::new(1)),
+                    }
+                }
+            };
+            let end = clamp_event_end(start, end, google_event.id.as_deref().unwrap_or_default());
+            // Such as reminders, which show up as instants
+            if end.start_instant() - start.start_instant() < config.min_event_duration {
+                return None;
+            }
+            let organizer = google_event
+                .organizer
+                .as_ref()
+                .filter(|_| full && config.include_organizer);
+            // Google keeps some exclusions in the recurrence, and lists others as occurrences
+            let mut event_exdates = google_event_recurrence_dates(google_event, "EXDATE");
+            if let Some(times) = google_event.id.as_deref().and_then(|id| exdates.get(id)) {
+                event_exdates.extend(times);
+                event_exdates.sort();
+                event_exdates.dedup();
+            }
+            Some(Event {
+                google_id: google_event.id.clone(),
+                calendar_id: Some(calendar_id.to_string()),
+                start,
+                end,
+                summary: sanitize_ical_text(
+                    &format!(
+                        "{}{}{}",
+                        config.summary_prefix,
+                        mirrored_summary(summary_mode, google_summary),
+                        config.summary_suffix
+                    ),
+                    config.max_summary_length,
+                ),
+                description: google_event.description.as_deref().filter(|_| full).map(
+                    |description| sanitize_ical_text(description, config.max_description_length),
+                ),
+                location: google_event
+                    .location
+                    .as_deref()
+                    .filter(|_| full)
+                    .map(|location| sanitize_ical_text(location, config.max_summary_length)),
+                rrule: google_event_rrule(google_event),
+                exdates: event_exdates,
+                rdates: google_event_recurrence_dates(google_event, "RDATE"),
+                transparent: google_event.transparency.as_deref() == Some("transparent"),
+                organizer: organizer.and_then(|organizer| organizer.email.clone()),
+                organizer_name: organizer
+                    .and_then(|organizer| organizer.display_name.as_deref())
+                    // Quotes, semicolons and newlines can't be escaped in a parameter value
+                    .map(|name| {
+                        sanitize_ical_text(name, config.max_summary_length)
+                            .replace(['"', ';', '\n'], "")
+                    }),
+                color: google_event
+                    .color_id
+                    .as_deref()
+                    .filter(|_| config.sync_colors)
+                    .and_then(google_color_name)
+                    .map(str::to_string),
+                // All-day events have no zone to keep
+                time_zone: google_event
+                    .start
+                    .as_ref()
+                    .and_then(|start| start.time_zone.as_deref())
+                    .filter(|_| config.serialize_tz && matches!(start, EventTime::Timed(_)))
+                    .and_then(|time_zone| time_zone.parse().ok()),
+                // Google leaves attendees out of events with only the organizer
+                attendee_count: google_event
+                    .attendees
+                    .as_ref()
+                    .filter(|_| {
+                        config.sync_attendee_count && google_event.attendees_omitted != Some(true)
+                    })
+                    .map(Vec::len),
+                private,
+                created: google_event.created,
+                last_modified: google_event.updated,
+            })
+        })
+        .collect()
+}
+
+/// Google sync tokens for each calendar, taken at the start of the last full sync. Google can tell
+/// from these whether a calendar has changed since, letting unchanged syncs be skipped.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct SyncTokens {
+    /// Unix timestamp of the last full sync
+    full_sync_at: i64,
+    tokens: HashMap<String, String>,
+}
+
+/// Full syncs still happen this often without any Google changes, as the sync window moves along
+/// with time, and others may have changed the CalDAV calendar.
+const FULL_SYNC_MAX_AGE_SECONDS: i64 = 60 * 60;
+
+fn sync_tokens_path(config: &Config) -> PathBuf {
+    Path::new(&config.google_calendar_secrets_dir)
+        .join(format!("sync_tokens{}.json", config.state_file_suffix))
+}
+
+fn load_sync_tokens(path: &Path) -> anyhow::Result<Option<SyncTokens>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    serde_json::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn save_sync_tokens(path: &Path, sync_tokens: &SyncTokens) -> anyhow::Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(sync_tokens)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Lists a calendar's changes since `sync_token`, or all of its events when there's no token,
+/// returning how many events were listed and the token to use next time.
+async fn list_google_changes(
+    config: &Config,
+    hub: &GoogleHub,
+    calendar_id: &str,
+    sync_token: Option<&str>,
+) -> anyhow::Result<(usize, String)> {
+    let mut count = 0;
+    let mut page_token: Option<String> = None;
+    loop {
+        // Sync tokens can't be combined with a time range, so this covers the whole calendar.
+        // Only ids are needed, to count the events.
+        let mut request = hub
+            .events()
+            .list(calendar_id)
+            .max_results(2500)
+            .single_events(!config.sync_recurrence)
+            .param("fields", "items(id),nextPageToken,nextSyncToken");
+        if let Some(sync_token) = sync_token {
+            request = request.sync_token(sync_token);
+        }
+        if let Some(token) = &page_token {
+            request = request.page_token(token);
+        }
+        let mut delegate = GoogleRateLimitDelegate::new(config);
+        let attempt_started = delegate.attempt_started.clone();
+        let result = with_google_timeout(
+            config,
+            Some(&attempt_started),
+            request.delegate(&mut delegate).doit(),
+        )
+        .await?
+        .1;
+
+        count += result.items.map_or(0, |items| items.len());
+        page_token = result.next_page_token.filter(|token| !token.is_empty());
+        if page_token.is_none() {
+            return result
+                .next_sync_token
+                .with_context(|| format!("No sync token returned for {}", calendar_id))
+                .map(|next_sync_token| (count, next_sync_token));
+        }
+    }
+}
+
+/// Whether Google refused our credentials, as when access was revoked. Retrying doesn't help until
+/// access is granted again.
+pub fn is_google_auth_error(error: &anyhow::Error) -> bool {
+    error.chain().any(
+        |cause| match cause.downcast_ref::<google_calendar3::Error>() {
+            Some(google_calendar3::Error::MissingToken(_)) => true,
+            Some(google_calendar3::Error::BadRequest(error)) => error["error"]["code"] == 401,
+            Some(google_calendar3::Error::Failure(response)) => response.status().as_u16() == 401,
+            _ => false,
+        },
+    )
+}
+
+fn is_google_gone(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<google_calendar3::Error>() {
+        Some(google_calendar3::Error::BadRequest(error)) => error["error"]["code"] == 410,
+        Some(google_calendar3::Error::Failure(response)) => response.status().as_u16() == 410,
+        _ => false,
+    }
+}
+
+/// Takes fresh sync tokens for each calendar, from the changes since the previous ones where
+/// there are any. Besides the tokens, returns whether a full sync can be skipped, as the last was
+/// recent and Google reports no changes since. The whole calendar is only listed for a token when
+/// there's none yet, or Google has expired the one we had.
+async fn refresh_sync_tokens(
+    config: &Config,
+    hub: &GoogleHub,
+    now: DateTime<Utc>,
+    previous: Option<&SyncTokens>,
+) -> anyhow::Result<(SyncTokens, bool)> {
+    let mut unchanged = previous.is_some_and(|previous| {
+        now.timestamp() - previous.full_sync_at < FULL_SYNC_MAX_AGE_SECONDS
+    });
+    let mut tokens = HashMap::new();
+    for calendar_id in &config.google_calendar_ids {
+        let sync_token = previous.and_then(|previous| previous.tokens.get(calendar_id));
+        let listed = match sync_token {
+            Some(sync_token) => {
+                match list_google_changes(config, hub, calendar_id, Some(sync_token)).await {
+                    Ok((changes, next_sync_token)) => {
+                        if changes > 0 {
+                            debug!("{} changes in Google calendar {}", changes, calendar_id);
+                            unchanged = false;
+                        }
+                        Some(next_sync_token)
+                    }
+                    // Google expires sync tokens now and then, after which a full sync is needed
+                    Err(e) if is_google_gone(&e) => {
+                        info!(
+                            "Sync token for Google calendar {} has expired, doing a full sync",
+                            calendar_id
+                        );
+                        None
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            None => None,
+        };
+        let sync_token = match listed {
+            Some(sync_token) => sync_token,
+            None => {
+                unchanged = false;
+                list_google_changes(config, hub, calendar_id, None).await?.1
+            }
+        };
+        tokens.insert(calendar_id.clone(), sync_token);
+    }
+    Ok((
+        SyncTokens {
+            full_sync_at: now.timestamp(),
+            tokens,
+        },
+        unchanged,
+    ))
+}
+
+#[derive(Debug, Default)]
+pub struct EventDiff<'a> {
+    pub to_delete: Vec<&'a EventWithCaldavUid>,
+    pub to_update: Vec<(&'a EventWithCaldavUid, &'a Event)>,
+    pub to_create: Vec<&'a Event>,
+}
+
+/// How many changes a sync found were needed to bring CalDAV in line with Google.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiffCounts {
+    pub to_delete: usize,
+    pub to_update: usize,
+    pub to_create: usize,
+    pub to_push: usize,
+}
+
+impl DiffCounts {
+    pub fn total(&self) -> usize {
+        self.to_delete + self.to_update + self.to_create + self.to_push
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total() == 0
+    }
+}
+
+/// What a sync found and did.
+#[derive(Debug, Default, Clone)]
+pub struct SyncReport {
+    pub google_events: usize,
+    pub caldav_events: usize,
+    /// Changes found to be needed, whether or not they were then made
+    pub planned: DiffCounts,
+    pub created: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    pub pushed: usize,
+    /// Google events whose CalDAV copy was already up to date
+    pub unchanged: usize,
+    /// Creates and updates skipped, as the server refused the event as too large
+    pub skipped: usize,
+    /// Why each change that failed to apply failed
+    pub errors: Vec<String>,
+    pub duration: Duration,
+}
+
+impl SyncReport {
+    /// Fails if any change failed to apply.
+    pub fn ensure_ok(&self) -> anyhow::Result<()> {
+        match self.errors.first() {
+            Some(first) => anyhow::bail!(
+                "{} of {} changes failed to apply, the first with: {}",
+                self.errors.len(),
+                self.planned.total(),
+                first
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds the counts of another sync, as done alongside this one.
+    fn absorb(&mut self, other: SyncReport) {
+        self.google_events += other.google_events;
+        self.caldav_events += other.caldav_events;
+        self.planned.to_delete += other.planned.to_delete;
+        self.planned.to_update += other.planned.to_update;
+        self.planned.to_create += other.planned.to_create;
+        self.planned.to_push += other.planned.to_push;
+        self.created += other.created;
+        self.updated += other.updated;
+        self.deleted += other.deleted;
+        self.pushed += other.pushed;
+        self.unchanged += other.unchanged;
+        self.skipped += other.skipped;
+        self.errors.extend(other.errors);
+        self.duration += other.duration;
+    }
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Sync finished in {:.1?}: {} Google events, {} CalDAV events, {} created, {} updated, \
+             {} deleted, {} unchanged, {} errors",
+            self.duration,
+            self.google_events,
+            self.caldav_events,
+            self.created,
+            self.updated,
+            self.deleted,
+            self.unchanged,
+            self.errors.len()
+        )?;
+        if self.skipped > 0 {
+            write!(f, ", {} skipped as too large", self.skipped)?;
+        }
+        if self.pushed > 0 {
+            write!(f, ", {} pushed to Google", self.pushed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Drops events whose content duplicates an earlier one, returning how many were dropped. These
+/// would otherwise each be created, as content matching only pairs events up one to one.
+fn dedupe_events(events: &mut Vec<Event>) -> usize {
+    let mut seen = HashSet::new();
+    let unique: Vec<bool> = events.iter().map(|event| seen.insert(event)).collect();
+    let before = events.len();
+    let mut unique = unique.into_iter();
+    events.retain(|_| unique.next().unwrap_or(true));
+    before - events.len()
+}
+
+/// Works out the changes needed to make the `current` CalDAV events match the `target` Google ones.
+pub fn find_diff<'a>(current: &'a [EventWithCaldavUid], target: &'a [Event]) -> EventDiff<'a> {
+    // Google event ids are only unique within a calendar, so they're qualified by calendar id
+    let target_by_google_id: HashMap<(Option<&str>, &str), usize> = target
+        .iter()
+        .enumerate()
+        .filter_map(|(i, event)| {
+            Some((
+                (event.calendar_id.as_deref(), event.google_id.as_deref()?),
+                i,
+            ))
+        })
+        .collect();
+    let mut matched = vec![false; target.len()];
+    let mut diff = EventDiff::default();
+
+    for event_with_caldav_uid in current {
+        let current_event = &event_with_caldav_uid.event;
+        let target_index = match current_event.google_id.as_deref() {
+            Some(google_id) => target_by_google_id
+                .get(&(current_event.calendar_id.as_deref(), google_id))
+                .copied()
+                .filter(|&i| !matched[i]),
+            // Events created before Google ids were recorded can only be matched by content.
+            // Unmanaged ones are never matched, as that would take over copies someone else made.
+            None if event_with_caldav_uid.managed => {
+                (0..target.len()).find(|&i| !matched[i] && target[i] == *current_event)
+            }
+            None => None,
+        };
+
+        match target_index {
+            Some(i) => {
+                matched[i] = true;
+                let target_event = &target[i];
+                if target_event != current_event
+                    || target_event.google_id != current_event.google_id
+                    || target_event.calendar_id != current_event.calendar_id
+                {
+                    diff.to_update.push((event_with_caldav_uid, target_event));
+                }
+            }
+            None if event_with_caldav_uid.managed => diff.to_delete.push(event_with_caldav_uid),
+            None => {}
+        }
+    }
+
+    diff.to_create = target
+        .iter()
+        .zip(matched)
+        .filter(|(_, matched)| !matched)
+        .map(|(event, _)| event)
+        .collect();
+
+    diff
+}
+
+/// Turns deletions paired with creations into updates of the deleted events' resources, leaving
+/// any left over as they are.
+fn update_in_place(diff: &mut EventDiff) {
+    let reused = diff.to_delete.len().min(diff.to_create.len());
+    let deleted = diff.to_delete.drain(..reused);
+    let created = diff.to_create.drain(..reused);
+    diff.to_update.extend(deleted.zip(created));
+}
+
+/// Deleting this many events never counts as deleting too large a fraction, so that small
+/// calendars can still be emptied.
+const MIN_DELETES_CAPPED: usize = 5;
+
+/// Refuses deletions that would wipe out more of the mirror than MAX_DELETE_FRACTION or
+/// MAX_DELETES allow. Google briefly listing no events, rather than failing, would otherwise get
+/// every event we wrote deleted.
+fn check_deletion_cap(
+    config: &Config,
+    diff: &EventDiff,
+    caldav_events: &[EventWithCaldavUid],
+) -> anyhow::Result<()> {
+    let deletes = diff.to_delete.len();
+    let managed = caldav_events.iter().filter(|e| e.managed).count();
+    let too_many = config.max_deletes.is_some_and(|max| deletes > max)
+        || (deletes > MIN_DELETES_CAPPED
+            && deletes as f64 > managed as f64 * config.max_delete_fraction);
+    if too_many {
+        error!(
+            "Refusing to delete {} of {} mirrored events, with {} events to create; Google may be \
+             returning incomplete results",
+            deletes,
+            managed,
+            diff.to_create.len()
+        );
+        anyhow::bail!(
+            "{} deletions exceed MAX_DELETE_FRACTION or MAX_DELETES, not applying changes",
+            deletes
+        );
+    }
+    Ok(())
+}
+
+/// How many syncs in a row an event can be rewritten before it's reported as churning.
+const CHURN_WARN_THRESHOLD: u32 = 3;
+
+/// Events written by the last sync of each collection, each with how many consecutive syncs it's
+/// been written in.
+static CHURN: Mutex<Option<HashMap<String, HashMap<u64, u32>>>> = Mutex::new(None);
+
+/// Warns about events that get written again on every sync. This happens when the copy we write
+/// doesn't read back as equal to the Google event, which would otherwise go unnoticed while the
+/// event is rewritten forever.
+fn detect_churn(collection_url: &str, diff: &EventDiff) {
+    let mut churn = CHURN.lock().unwrap();
+    let churn = churn.get_or_insert_with(HashMap::new);
+    let previous = churn.remove(collection_url).unwrap_or_default();
+    let mut current = HashMap::new();
+    let written = diff
+        .to_create
+        .iter()
+        .copied()
+        .chain(diff.to_update.iter().map(|(_, event)| *event));
+    for event in written {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        event.google_id.hash(&mut hasher);
+        event.hash(&mut hasher);
+        let key = hasher.finish();
+
+        let count = previous.get(&key).copied().unwrap_or(0) + 1;
+        if count >= CHURN_WARN_THRESHOLD {
+            warn!(
+                "Event {} has been written in {} consecutive syncs, so its CalDAV copy probably \
+                 doesn't read back the same (check escaping and time zones): {:?}",
+                describe_event(event),
+                count,
+                event
+            );
+        }
+        current.insert(key, count);
+    }
+    churn.insert(collection_url.to_string(), current);
+}
+
+/// Wraps `text` in an ANSI color escape, if `color` is set.
+fn paint(text: &str, ansi_code: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", ansi_code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Prints the diff for a person to read, one line per change grouped by kind.
+fn print_diff_summary(diff: &EventDiff) {
+    // See https://no-color.org
+    let no_color = match std::env::var_os("NO_COLOR") {
+        Some(value) => !value.is_empty(),
+        None => false,
+    };
+    let color = std::io::stdout().is_terminal() && !no_color;
+
+    if diff.to_delete.is_empty() && diff.to_update.is_empty() && diff.to_create.is_empty() {
+        println!("No changes");
+        return;
+    }
+    for event in &diff.to_delete {
+        println!(
+            "{} {}",
+            paint("-", "31", color),
+            describe_event(&event.event)
+        );
+    }
+    for (caldav_event, event) in &diff.to_update {
+        println!(
+            "{} {} -> {}",
+            paint("~", "33", color),
+            describe_event(&caldav_event.event),
+            describe_event(event)
+        );
+    }
+    for event in &diff.to_create {
+        println!("{} {}", paint("+", "32", color), describe_event(event));
+    }
+    let net = diff.to_create.len() as i64 - diff.to_delete.len() as i64;
+    println!(
+        "{} deleted, {} updated, {} created ({:+} events)",
+        diff.to_delete.len(),
+        diff.to_update.len(),
+        diff.to_create.len(),
+        net
+    );
+}
+
+fn event_json(caldav_uid: Option<&str>, event: &Event) -> serde_json::Value {
+    serde_json::json!({
+        "uid": caldav_uid,
+        "summary": event.summary,
+        "start": event.start.to_json(),
+        "end": event.end.to_json(),
+    })
+}
+
+/// Appends the diff to `path` as one line of JSON, so that the file is a JSONL stream with one
+/// record per sync:
+///
+/// `{"timestamp": <RFC 3339>, "to_create": [<event>], "to_update": [{"from": <event>, "to":
+/// <event>}], "to_delete": [<event>]}`
+///
+/// where each event is `{"uid", "summary", "start", "end"}`. Times are RFC 3339, or a plain date
+/// for all-day events. Events to create have no uid yet, so theirs is null.
+fn export_diff(path: &Path, timestamp: DateTime<Utc>, diff: &EventDiff) -> anyhow::Result<()> {
+    let record = serde_json::json!({
+        "timestamp": timestamp.to_rfc3339(),
+        "to_create": diff
+            .to_create
+            .iter()
+            .map(|event| event_json(None, event))
+            .collect::<Vec<_>>(),
+        "to_update": diff
+            .to_update
+            .iter()
+            .map(|(caldav_event, event)| {
+                serde_json::json!({
+                    "from": event_json(Some(&caldav_event.caldav_uid), &caldav_event.event),
+                    "to": event_json(Some(&caldav_event.caldav_uid), event),
+                })
+            })
+            .collect::<Vec<_>>(),
+        "to_delete": diff
+            .to_delete
+            .iter()
+            .map(|event| event_json(Some(&event.caldav_uid), &event.event))
+            .collect::<Vec<_>>(),
+    });
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    writeln!(file, "{}", record).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+const CALENDAR_DISCOVERY_REQUEST: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+  <d:prop>
+    <d:resourcetype />
+    <d:current-user-principal />
+    <c:calendar-home-set />
+  </d:prop>
+</d:propfind>"#;
+
+#[derive(Debug)]
+struct DavResource {
+    href: Url,
+    is_calendar: bool,
+    principal: Option<Url>,
+    calendar_home_set: Option<Url>,
+}
+
+fn find_dav_prop<'a>(response: &'a xmltree::Element, name: &str) -> Option<&'a xmltree::Element> {
+    response
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .filter(|element| element.name == "propstat")
+        .filter_map(|propstat| propstat.get_child("prop"))
+        .find_map(|prop| prop.get_child(name))
+}
+
+fn find_dav_href(element: &xmltree::Element, base: &Url) -> Option<Url> {
+    base.join(element.get_child("href")?.get_text()?.trim())
+        .ok()
+}
+
+/// How far our clock may be from the CalDAV server's before it's warned about.
+const MAX_CLOCK_SKEW: chrono::TimeDelta = chrono::TimeDelta::seconds(60);
+
+/// Warns when the server's Date header is far from our own clock. A skewed clock shifts the sync
+/// window and DTSTAMPs, so events bunch up at the wrong edge of the window.
+fn check_clock_skew(response: &ureq::Response, url: &Url) {
+    let Some(date) = response.header("Date") else {
+        return;
+    };
+    let server_now = match DateTime::parse_from_rfc2822(date) {
+        Ok(server_now) => server_now.to_utc(),
+        Err(e) => {
+            debug!("Invalid Date '{}' from {}: {}", date, url, e);
+            return;
+        }
+    };
+    let skew = Utc::now() - server_now;
+    if skew.abs() > MAX_CLOCK_SKEW {
+        warn!(
+            "Local clock is {}s {} {}'s, check the system time (NTP) or the sync window will be off",
+            skew.num_seconds().abs(),
+            if skew > chrono::TimeDelta::zero() {
+                "ahead of"
+            } else {
+                "behind"
+            },
+            url.host_str().unwrap_or(url.as_str())
+        );
+    }
+}
+
+async fn propfind_dav_resources(
+    config: &Config,
+    agent: &ureq::Agent,
+    url: &Url,
+    depth: &str,
+) -> anyhow::Result<Vec<DavResource>> {
+    let request = agent
+        .request("PROPFIND", url.as_str())
+        .set("Content-Type", "application/xml; charset=utf-8")
+        .set("Depth", depth);
+    let response = with_retry(config, request, Some(CALENDAR_DISCOVERY_REQUEST))
+        .await
+        .with_context(|| format!("PROPFIND {} failed", url))?;
+    check_clock_skew(&response, url);
+    let multistatus = xmltree::Element::parse(response.into_reader())
+        .with_context(|| format!("Failed to parse PROPFIND response from {}", url))?;
+
+    Ok(multistatus
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .filter(|element| element.name == "response")
+        .filter_map(|response| {
+            Some(DavResource {
+                href: find_dav_href(response, url)?,
+                is_calendar: find_dav_prop(response, "resourcetype")
+                    .is_some_and(|resourcetype| resourcetype.get_child("calendar").is_some()),
+                principal: find_dav_prop(response, "current-user-principal")
+                    .and_then(|prop| find_dav_href(prop, url)),
+                calendar_home_set: find_dav_prop(response, "calendar-home-set")
+                    .and_then(|prop| find_dav_href(prop, url)),
+            })
+        })
+        .collect())
+}
+
+/// Accepts any server certificate, for CALDAV_INSECURE_SKIP_VERIFY. Signatures are still checked,
+/// so the handshake itself is sound, but the server's identity isn't.
+#[derive(Debug)]
+struct SkipServerVerification(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Builds the TLS configuration for the CalDAV server, if it needs more than ureq's defaults: an
+/// extra root from CALDAV_CA_CERT, or no verification at all.
+fn caldav_tls_config(config: &Config) -> anyhow::Result<Option<rustls::ClientConfig>> {
+    if config.caldav_ca_cert.is_none() && !config.caldav_insecure_skip_verify {
+        return Ok(None);
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .with_context(|| "Failed to set up TLS")?;
+
+    if config.caldav_insecure_skip_verify {
+        warn!(
+            "CALDAV_INSECURE_SKIP_VERIFY is set: the CalDAV server's TLS certificate is NOT being \
+             verified, so the connection can be intercepted. Only use this for testing!"
+        );
+        return Ok(Some(
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(SkipServerVerification(provider)))
+                .with_no_client_auth(),
+        ));
+    }
+
+    let mut roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    if let Some(path) = &config.caldav_ca_cert {
+        let certs = rustls::pki_types::CertificateDer::pem_file_iter(path)
+            .with_context(|| format!("Failed to read CALDAV_CA_CERT {}", path.display()))?;
+        for cert in certs {
+            let cert =
+                cert.with_context(|| format!("Failed to parse CALDAV_CA_CERT {}", path.display()))?;
+            roots
+                .add(cert)
+                .with_context(|| format!("Invalid certificate in {}", path.display()))?;
+        }
+    }
+    Ok(Some(
+        builder.with_root_certificates(roots).with_no_client_auth(),
+    ))
+}
+
+/// Builds the agent for talking to the CalDAV server, through a proxy if one is configured. Every
+/// connect, read and write is bounded by HTTP_TIMEOUT_SECONDS. The agent pools its connections, so
+/// it's meant to be built once and shared by every sync.
+pub fn caldav_agent(config: &Config) -> anyhow::Result<ureq::Agent> {
+    let host = Url::parse(&config.caldav_uri)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let mut builder = ureq::AgentBuilder::new()
+        .user_agent(&config.user_agent)
+        .timeout_connect(config.http_timeout)
+        .timeout_read(config.http_timeout)
+        .timeout_write(config.http_timeout);
+    if let Some(tls_config) = caldav_tls_config(config)? {
+        builder = builder.tls_config(Arc::new(tls_config));
+    }
+    match &config.caldav_proxy {
+        Some(proxy) if !is_no_proxy(&config.no_proxy, &host) => {
+            let proxy = ureq::Proxy::new(proxy.as_str())
+                .with_context(|| format!("Invalid CalDAV proxy {}", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+        _ => {}
+    }
+    Ok(builder.build())
+}
+
+/// Finds the calendar collection to sync with, falling back to CALDAV_URI itself.
+async fn calendar_collection_url(config: &Config, agent: &ureq::Agent) -> String {
+    match discover_calendar_collection(config, agent, &config.caldav_uri).await {
+        Ok(collection_url) => collection_url,
+        Err(e) => {
+            warn!(
+                "Calendar collection discovery failed, using CALDAV_URI as-is: {:#}",
+                e
+            );
+            config.caldav_uri.clone()
+        }
+    }
+}
+
+/// Finds the calendar collection that events should be written to, starting from `caldav_uri`,
+/// which may point at the collection itself, a calendar-home-set, or a principal.
+async fn discover_calendar_collection(
+    config: &Config,
+    agent: &ureq::Agent,
+    caldav_uri: &str,
+) -> anyhow::Result<String> {
+    let base =
+        Url::parse(caldav_uri).with_context(|| format!("Invalid CALDAV_URI {}", caldav_uri))?;
+    let resource = propfind_dav_resources(config, agent, &base, "0")
+        .await?
+        .into_iter()
+        .next()
+        .with_context(|| format!("Empty PROPFIND response from {}", base))?;
+
+    let collection = if resource.is_calendar {
+        resource.href
+    } else {
+        let calendar_home_set = match (resource.calendar_home_set, resource.principal) {
+            (Some(calendar_home_set), _) => calendar_home_set,
+            (None, Some(principal)) => propfind_dav_resources(config, agent, &principal, "0")
+                .await?
+                .into_iter()
+                .find_map(|r| r.calendar_home_set)
+                .with_context(|| format!("Principal {} has no calendar-home-set", principal))?,
+            (None, None) => anyhow::bail!(
+                "{} is not a calendar collection and has no principal or calendar-home-set",
+                base
+            ),
+        };
+        let collection = propfind_dav_resources(config, agent, &calendar_home_set, "1")
+            .await?
+            .into_iter()
+            .find(|r| r.is_calendar)
+            .with_context(|| format!("No calendar collections found in {}", calendar_home_set))?
+            .href;
+        info!(
+            "Using calendar collection {} from {}",
+            collection, calendar_home_set
+        );
+        collection
+    };
+
+    let mut collection = collection.to_string();
+    if !collection.ends_with('/') {
+        collection.push('/');
+    }
+    Ok(collection)
+}
+
+fn parse_digest_challenge(challenge: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = challenge.trim();
+    while let Some((key, after_key)) = rest.split_once('=') {
+        let after_key = after_key.trim_start();
+        let (value, after_value) = match after_key.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => match after_key.find(',') {
+                Some(end) => (after_key[..end].trim(), &after_key[end..]),
+                None => (after_key.trim(), ""),
+            },
+        };
+        params.insert(key.trim().to_lowercase(), value.to_string());
+        rest = after_value
+            .trim_start()
+            .trim_start_matches(',')
+            .trim_start();
+    }
+    params
+}
+
+fn md5_hex(data: &str) -> String {
+    format!("{:x}", Md5::digest(data.as_bytes()))
+}
+
+/// Answers an HTTP Digest challenge (RFC 7616) using MD5, the only algorithm servers commonly use.
+fn digest_auth_header(
+    challenge: &str,
+    method: &str,
+    url: &str,
+    username: &str,
+    password: &str,
+) -> anyhow::Result<String> {
+    let params = parse_digest_challenge(challenge);
+    if let Some(algorithm) = params.get("algorithm") {
+        if !algorithm.eq_ignore_ascii_case("MD5") {
+            anyhow::bail!("Unsupported digest algorithm {}", algorithm);
+        }
+    }
+    let realm = params.get("realm").map(String::as_str).unwrap_or("");
+    let nonce = params
+        .get("nonce")
+        .with_context(|| "Digest challenge is missing a nonce")?;
+    let url = Url::parse(url)?;
+    let uri = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    let ha1 = md5_hex(&format!("{}:{}:{}", username, realm, password));
+    let ha2 = md5_hex(&format!("{}:{}", method, uri));
+    let mut header = format!(
+        r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm=MD5"#,
+        username, realm, nonce, uri
+    );
+    let supports_qop_auth = params
+        .get("qop")
+        .is_some_and(|qop| qop.split(',').any(|q| q.trim() == "auth"));
+    if supports_qop_auth {
+        let cnonce: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let nc = "00000001";
+        let response = md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+        header.push_str(&format!(
+            r#", qop=auth, nc={}, cnonce="{}", response="{}""#,
+            nc, cnonce, response
+        ));
+    } else {
+        let response = md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2));
+        header.push_str(&format!(r#", response="{}""#, response));
+    }
+    if let Some(opaque) = params.get("opaque") {
+        header.push_str(&format!(r#", opaque="{}""#, opaque));
+    }
+    Ok(header)
+}
+
+/// Sends a CalDAV request with Basic auth when credentials are configured, answering a Digest
+/// challenge instead if that's what the server asks for.
+fn send_caldav_request(
+    credentials: Option<&(String, String)>,
+    request: ureq::Request,
+    body: Option<&str>,
+) -> Result<ureq::Response, Box<ureq::Error>> {
+    let send = |request: ureq::Request| {
+        match body {
+            Some(body) => request.send_string(body),
+            None => request.call(),
+        }
+        .map_err(Box::new)
+    };
+    let (username, password) = match credentials {
+        Some(credentials) => credentials,
+        None => return send(request),
+    };
+
+    let basic =
+        base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+    match send(
+        request
+            .clone()
+            .set("Authorization", &format!("Basic {}", basic)),
+    ) {
+        Err(e) => match *e {
+            ureq::Error::Status(401, response) => {
+                let challenge = response
+                    .all("WWW-Authenticate")
+                    .into_iter()
+                    .find_map(|challenge| challenge.strip_prefix("Digest "))
+                    .map(|challenge| {
+                        digest_auth_header(
+                            challenge,
+                            request.method(),
+                            request.url(),
+                            username,
+                            password,
+                        )
+                    });
+                match challenge {
+                    Some(Ok(header)) => send(request.set("Authorization", &header)),
+                    Some(Err(e)) => {
+                        warn!("Unable to answer digest challenge: {:#}", e);
+                        Err(Box::new(ureq::Error::Status(401, response)))
+                    }
+                    None => Err(Box::new(ureq::Error::Status(401, response))),
+                }
+            }
+            e => Err(Box::new(e)),
+        },
+        result => result,
+    }
+}
+
+/// Whether a failed request is worth sending again. A transport error may come after the server
+/// acted on the request, so those are only retried when `repeatable` says sending it twice is
+/// harmless; a repeated POST could create the event twice.
+fn is_retryable_caldav_error(error: &ureq::Error, repeatable: bool) -> bool {
+    match error {
+        ureq::Error::Status(status, _) => *status == 429 || *status >= 500,
+        ureq::Error::Transport(transport) => {
+            repeatable
+                && matches!(
+                    transport.kind(),
+                    ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+                )
+        }
+    }
+}
+
+/// The longest wait between retries, however many there have been.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Exponential backoff with jitter for the given (zero-based) retry attempt.
+fn retry_backoff(attempt: u32) -> Duration {
+    const BASE_BACKOFF_MS: u64 = 500;
+
+    let backoff_ms = 2u64
+        .checked_pow(attempt)
+        .map_or(u64::MAX, |factor| factor.saturating_mul(BASE_BACKOFF_MS))
+        .min(MAX_RETRY_BACKOFF.as_millis() as u64);
+    let jitter_ms = thread_rng().gen_range(0..=backoff_ms / 2);
+    Duration::from_millis(backoff_ms + jitter_ms).min(MAX_RETRY_BACKOFF)
+}
+
+/// Runs a CalDAV request, retrying transient failures with exponential backoff and jitter.
+async fn with_retry(
+    config: &Config,
+    request: ureq::Request,
+    body: Option<&str>,
+) -> Result<ureq::Response, Box<ureq::Error>> {
+    let max_retries = config.caldav_max_retries;
+    let repeatable = request.method() != "POST"
+        || request.header("If-Match").is_some()
+        || request.header("If-None-Match").is_some();
+    let mut attempt = 0;
+    loop {
+        // ureq blocks, so requests are sent from the blocking pool to let several run at once
+        let credentials = config.caldav_credentials.clone();
+        let attempt_request = request.clone();
+        let attempt_body = body.map(|body| body.to_string());
+        let result = tokio::task::spawn_blocking(move || {
+            send_caldav_request(
+                credentials.as_ref(),
+                attempt_request,
+                attempt_body.as_deref(),
+            )
+        })
+        .await
+        .expect("CalDAV request task panicked");
+        match result {
+            Err(e) if attempt < max_retries && is_retryable_caldav_error(&e, repeatable) => {
+                let backoff = retry_backoff(attempt);
+                warn!(
+                    "CalDAV request failed ({}), retrying in {:?} ({}/{})",
+                    e,
+                    backoff,
+                    attempt + 1,
+                    max_retries
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The longest of an event's free text fields, which is the likeliest reason for a server to find
+/// it too large.
+fn largest_event_field(event: &Event) -> (&'static str, usize) {
+    [
+        ("SUMMARY", Some(&event.summary)),
+        ("DESCRIPTION", event.description.as_ref()),
+        ("LOCATION", event.location.as_ref()),
+    ]
+    .into_iter()
+    .filter_map(|(name, text)| Some((name, text?.chars().count())))
+    .max_by_key(|&(_, length)| length)
+    .unwrap_or(("SUMMARY", 0))
+}
+
+/// Whether a change was made, or skipped as one the server would never accept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOutcome {
+    Written,
+    Skipped,
+}
+
+impl From<()> for WriteOutcome {
+    fn from(_: ()) -> WriteOutcome {
+        WriteOutcome::Written
+    }
+}
+
+/// Whether an error response refuses a resource as too large: a 413, or the max-resource-size
+/// precondition CalDAV servers give (RFC 4791 section 5.3.2.1).
+fn is_too_large_response(status: u16, body: &str) -> bool {
+    status == 413
+        || xmltree::Element::parse(body.as_bytes()).is_ok_and(|error| {
+            error.name == "error" && error.get_child("max-resource-size").is_some()
+        })
+}
+
+/// Sends the iCal data of `event`. Events the server refuses as too large are logged and skipped,
+/// returning None, rather than failing the sync, as they'd only be refused again the next time.
+async fn send_event(
+    config: &Config,
+    request: ureq::Request,
+    event: &Event,
+    ical: &str,
+) -> anyhow::Result<Option<ureq::Response>> {
+    let url = request.url().to_string();
+    // Some servers refuse events sent as anything else
+    let request = request.set("Content-Type", "text/calendar; charset=utf-8");
+    let error = match with_retry(config, request, Some(ical)).await {
+        Ok(response) => return Ok(Some(response)),
+        Err(e) => *e,
+    };
+    match error {
+        // A failed precondition is only told apart from other refusals by the body
+        ureq::Error::Status(status @ (400 | 403 | 413), response) => {
+            let body = response.into_string().unwrap_or_default();
+            if !is_too_large_response(status, &body) {
+                anyhow::bail!("{}: status code {}: {}", url, status, body.trim());
+            }
+            let (field, length) = largest_event_field(event);
+            warn!(
+                "Skipping event {}, which the server refused as too large ({}); its largest field \
+                 is {} at {} characters",
+                describe_event(event),
+                status,
+                field,
+                length
+            );
+            Ok(None)
+        }
+        error => Err(error.into()),
+    }
+}
+
+/// Writes `event` to a new resource in the collection at `caldav_url`.
+pub async fn create_caldav_event(
+    config: &Config,
+    agent: &ureq::Agent,
+    caldav_url: &str,
+    event: &Event,
+    state: Option<&Mutex<StateStore>>,
+) -> anyhow::Result<WriteOutcome> {
+    let random_uid: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+    let (uri, request) = match config.caldav_create_method {
+        CreateMethod::Put => {
+            let uri = join_caldav_path(caldav_url, &format!("{}.ics", random_uid))?;
+            (uri.clone(), agent.put(&uri))
+        }
+        CreateMethod::Post => (caldav_url.to_string(), agent.post(caldav_url)),
+    };
+    debug!("Creating event {} at {}", describe_event(event), uri);
+
+    let ical = fold_ical_lines(&event.to_ical(&random_uid).serialize());
+    let Some(response) = send_event(config, request, event, &ical)
+        .await
+        .with_context(|| format!("Failed to create event {}", describe_event(event)))?
+    else {
+        return Ok(WriteOutcome::Skipped);
+    };
+
+    // Servers may store the event somewhere other than where it was put. Later syncs find it
+    // there through the REPORT.
+    let mut stored_at = uri.clone();
+    if let Some(location) = response.header("Location") {
+        match Url::parse(&uri).and_then(|uri| uri.join(location)) {
+            Ok(location) if location.as_str() != uri => {
+                debug!("Event {} was stored at {}", describe_event(event), location);
+                stored_at = location.to_string();
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Invalid Location '{}' for created event: {}", location, e),
+        }
+    } else if config.caldav_create_method == CreateMethod::Post {
+        // The REPORT will still find it, but we can't tell where until then
+        warn!(
+            "Server gave no Location for created event {}",
+            describe_event(event)
+        );
+        return Ok(WriteOutcome::Written);
+    }
+
+    if let Some(state) = state {
+        state.lock().unwrap().record(&stored_at, event);
+    }
+
+    Ok(WriteOutcome::Written)
+}
+
+/// Characters left as they are in resource names; everything else is percent-encoded. This is
+/// stricter than URLs require, as servers disagree on what characters like `@` mean in a path.
+const RESOURCE_NAME_CHARS: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// URL of the resource `name` in the collection at `base`. The base is taken to be a collection
+/// whether or not it ends with a slash, and the name is percent-encoded as a single path segment.
+fn join_caldav_path(base: &str, name: &str) -> anyhow::Result<String> {
+    let mut url = Url::parse(base).with_context(|| format!("Invalid collection URL {}", base))?;
+    if url.cannot_be_a_base() {
+        anyhow::bail!("Collection URL {} can't have a path", base);
+    }
+    let path = format!(
+        "{}/{}",
+        url.path().trim_end_matches('/'),
+        percent_encoding::utf8_percent_encode(name, RESOURCE_NAME_CHARS)
+    );
+    url.set_path(&path);
+    Ok(url.to_string())
+}
+
+fn caldav_event_url(caldav_url: &str, caldav_event: &EventWithCaldavUid) -> anyhow::Result<String> {
+    match &caldav_event.href {
+        Some(href) => Ok(href.to_string()),
+        None => join_caldav_path(caldav_url, &format!("{}.ics", caldav_event.caldav_uid)),
+    }
+}
+
+async fn update_caldav_event(
+    config: &Config,
+    agent: &ureq::Agent,
+    caldav_url: &str,
+    caldav_event: &EventWithCaldavUid,
+    event: &Event,
+    state: Option<&Mutex<StateStore>>,
+) -> anyhow::Result<WriteOutcome> {
+    let uri = caldav_event_url(caldav_url, caldav_event)?;
+    debug!(
+        "Updating event {} to {} at {}",
+        describe_event(&caldav_event.event),
+        describe_event(event),
+        uri
+    );
+
+    let ical = fold_ical_lines(&event.to_ical(&caldav_event.caldav_uid).serialize());
+    let sent = send_event(config, agent.put(&uri), event, &ical)
+        .await
+        .with_context(|| format!("Failed to update event {}", describe_event(event)))?;
+    if sent.is_none() {
+        return Ok(WriteOutcome::Skipped);
+    }
+
+    if let Some(state) = state {
+        state.lock().unwrap().record(&uri, event);
+    }
+
+    Ok(WriteOutcome::Written)
+}
+
+/// Deletes the resource holding `caldav_event`, unless it changed since it was fetched.
+pub async fn delete_caldav_event(
+    config: &Config,
+    agent: &ureq::Agent,
+    caldav_url: &str,
+    caldav_event: &EventWithCaldavUid,
+    state: Option<&Mutex<StateStore>>,
+) -> anyhow::Result<()> {
+    let uri = caldav_event_url(caldav_url, caldav_event)?;
+    debug!(
+        "Deleting event {} at {}",
+        describe_event(&caldav_event.event),
+        uri
+    );
+
+    // Only delete the version we saw, rather than clobbering one changed since it was fetched
+    let mut request = agent.delete(&uri);
+    if let Some(etag) = &caldav_event.etag {
+        request = request.set("If-Match", etag);
+    }
+    match with_retry(config, request, None).await {
+        Err(e) if matches!(*e, ureq::Error::Status(412, _)) => {
+            warn!(
+                "Not deleting event {}, as it changed on the server since it was fetched",
+                describe_event(&caldav_event.event)
+            );
+        }
+        result => {
+            result.with_context(|| {
+                format!(
+                    "Failed to delete event {}",
+                    describe_event(&caldav_event.event)
+                )
+            })?;
+            if let Some(state) = state {
+                state.lock().unwrap().forget(&uri);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Records which CalDAV resource each Google event was written to, and what was written. This
+/// identifies our events on servers that drop the X- properties recording their Google ids, and
+/// avoids rewriting events that a server stores in a different form than it was given.
+pub struct StateStore {
+    connection: rusqlite::Connection,
+}
+
+impl StateStore {
+    fn open(path: &Path) -> anyhow::Result<StateStore> {
+        let connection = rusqlite::Connection::open(path)
+            .with_context(|| format!("Failed to open state database {}", path.display()))?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS events (
+                    calendar_id TEXT NOT NULL,
+                    google_id TEXT NOT NULL,
+                    caldav_href TEXT NOT NULL UNIQUE,
+                    content_hash TEXT NOT NULL,
+                    PRIMARY KEY (calendar_id, google_id)
+                );
+                CREATE TABLE IF NOT EXISTS backfill_progress (
+                    caldav_uri TEXT NOT NULL PRIMARY KEY,
+                    days INTEGER NOT NULL
+                )",
+            )
+            .with_context(|| format!("Failed to set up state database {}", path.display()))?;
+        Ok(StateStore { connection })
+    }
+
+    fn content_hash(event: &Event) -> String {
+        // Fields added since are only appended when set, keeping the hashes of existing events
+        let time_zone = event
+            .time_zone
+            .map(|tz| format!(";tz={}", tz.name()))
+            .unwrap_or_default();
+        let attendee_count = event
+            .attendee_count
+            .map(|count| format!(";{}", count))
+            .unwrap_or_default();
+        let private = if event.private { ";private" } else { "" };
+        let rdates = if event.rdates.is_empty() {
+            String::new()
+        } else {
+            format!(";rdates={:?}", event.rdates)
+        };
+        md5_hex(&format!(
+            "{:?}{}{}{}{}",
+            (
+                event.start,
+                event.end,
+                &event.summary,
+                &event.description,
+                &event.location,
+                &event.rrule,
+                &event.exdates,
+                event.transparent,
+                &event.organizer,
+                &event.organizer_name,
+                &event.color,
+            ),
+            time_zone,
+            attendee_count,
+            private,
+            rdates
+        ))
+    }
+
+    /// Fills in the Google ids of events the server didn't keep them for.
+    fn identify(&self, caldav_events: &mut [EventWithCaldavUid]) -> anyhow::Result<()> {
+        let mut query = self
+            .connection
+            .prepare("SELECT calendar_id, google_id FROM events WHERE caldav_href = ?1")?;
+        for caldav_event in caldav_events {
+            let href = match &caldav_event.href {
+                Some(href) if caldav_event.event.google_id.is_none() => href,
+                _ => continue,
+            };
+            let ids = query
+                .query_row([href.as_str()], |row| Ok((row.get(0)?, row.get(1)?)))
+                .optional()?;
+            if let Some((calendar_id, google_id)) = ids {
+                caldav_event.event.calendar_id = Some(calendar_id);
+                caldav_event.event.google_id = Some(google_id);
+                caldav_event.managed = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `event` is exactly what was last written to the resource at `href`.
+    fn is_written(&self, href: &str, event: &Event) -> anyhow::Result<bool> {
+        let content_hash: Option<String> = self
+            .connection
+            .query_row(
+                "SELECT content_hash FROM events WHERE caldav_href = ?1",
+                [href],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(content_hash == Some(StateStore::content_hash(event)))
+    }
+
+    /// How many days either side of now the collection at `caldav_uri` has been backfilled, or
+    /// zero if it hasn't been started.
+    fn backfilled_days(&self, caldav_uri: &str) -> anyhow::Result<i64> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT days FROM backfill_progress WHERE caldav_uri = ?1",
+                [caldav_uri],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    fn record_backfilled_days(&self, caldav_uri: &str, days: i64) -> anyhow::Result<()> {
+        self.connection.execute(
+            "INSERT INTO backfill_progress (caldav_uri, days) VALUES (?1, ?2)
+                ON CONFLICT (caldav_uri) DO UPDATE SET days = excluded.days",
+            rusqlite::params![caldav_uri, days],
+        )?;
+        Ok(())
+    }
+
+    fn record(&self, href: &str, event: &Event) {
+        let (calendar_id, google_id) = match (&event.calendar_id, &event.google_id) {
+            (Some(calendar_id), Some(google_id)) => (calendar_id, google_id),
+            _ => return,
+        };
+        // Replacing also clears out any other event previously recorded at the same href
+        let result = self.connection.execute(
+            "INSERT OR REPLACE INTO events (calendar_id, google_id, caldav_href, content_hash)
+                VALUES (?1, ?2, ?3, ?4)",
+            [
+                calendar_id.as_str(),
+                google_id.as_str(),
+                href,
+                &StateStore::content_hash(event),
+            ],
+        );
+        if let Err(e) = result {
+            warn!("Unable to record {} in state database: {}", href, e);
+        }
+    }
+
+    fn forget(&self, href: &str) {
+        if let Err(e) = self
+            .connection
+            .execute("DELETE FROM events WHERE caldav_href = ?1", [href])
+        {
+            warn!("Unable to remove {} from state database: {}", href, e);
+        }
+    }
+}
+
+struct Metrics {
+    sync_runs: AtomicU64,
+    sync_errors: AtomicU64,
+    events_created: AtomicU64,
+    events_updated: AtomicU64,
+    events_deleted: AtomicU64,
+    events_pushed: AtomicU64,
+    last_successful_sync: AtomicI64,
+}
+
+static METRICS: Metrics = Metrics {
+    sync_runs: AtomicU64::new(0),
+    sync_errors: AtomicU64::new(0),
+    events_created: AtomicU64::new(0),
+    events_updated: AtomicU64::new(0),
+    events_deleted: AtomicU64::new(0),
+    events_pushed: AtomicU64::new(0),
+    last_successful_sync: AtomicI64::new(0),
+};
+
+impl Metrics {
+    /// Renders the metrics in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let metrics = [
+            (
+                "gcal_sync_runs_total",
+                "counter",
+                "Number of syncs attempted",
+                self.sync_runs.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "gcal_sync_errors_total",
+                "counter",
+                "Number of syncs that failed",
+                self.sync_errors.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "gcal_events_created_total",
+                "counter",
+                "Number of CalDAV events created",
+                self.events_created.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "gcal_events_updated_total",
+                "counter",
+                "Number of CalDAV events updated",
+                self.events_updated.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "gcal_events_deleted_total",
+                "counter",
+                "Number of CalDAV events deleted",
+                self.events_deleted.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "gcal_events_pushed_total",
+                "counter",
+                "Number of CalDAV events created on Google",
+                self.events_pushed.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "gcal_last_successful_sync_timestamp",
+                "gauge",
+                "Unix time of the last successful sync",
+                self.last_successful_sync.load(Ordering::Relaxed),
+            ),
+        ];
+        metrics
+            .iter()
+            .map(|(name, kind, help, value)| {
+                format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n")
+            })
+            .collect()
+    }
+}
+
+/// Serves the sync metrics in the Prometheus text format.
+pub async fn serve_metrics(addr: &str) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind metrics server to {}", addr))?;
+    info!("Serving metrics on {}", addr);
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            // Every path serves the metrics, so the request itself doesn't matter
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await;
+
+            let body = METRICS.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                warn!("Failed to write metrics response: {}", e);
+            }
+        });
+    }
+}
+
+/// Finds the events that were added on CalDAV rather than mirrored from Google, and so should be
+/// created on Google. Those at the same time as a Google event but titled differently are left
+/// alone, as it's not clear which is right.
+fn find_push<'a>(
+    caldav_events: &'a [EventWithCaldavUid],
+    google_events: &[Event],
+    diff: &EventDiff,
+) -> Vec<&'a EventWithCaldavUid> {
+    caldav_events
+        .iter()
+        .filter(|e| !e.managed && e.event.google_id.is_none())
+        // Unmanaged events matching a Google event's content are already taken over by the diff
+        .filter(|e| {
+            !diff
+                .to_update
+                .iter()
+                .any(|(updated, _)| std::ptr::eq(*updated, *e))
+        })
+        .filter(|e| {
+            let conflict = google_events
+                .iter()
+                .find(|g| g.start == e.event.start && g.end == e.event.end);
+            match conflict {
+                Some(google_event) if google_event.summary == e.event.summary => false,
+                Some(google_event) => {
+                    warn!(
+                        "Not pushing event {}, as it conflicts with Google event {}; resolve \
+                         this by hand",
+                        describe_event(&e.event),
+                        describe_event(google_event)
+                    );
+                    false
+                }
+                None => true,
+            }
+        })
+        .collect()
+}
+
+/// Creates a CalDAV-only event on the first configured Google calendar, then records the new
+/// Google id on the CalDAV copy, so that it's mirrored from then on rather than pushed again.
+async fn push_caldav_event(
+    config: &Config,
+    hub: &GoogleHub,
+    agent: &ureq::Agent,
+    caldav_url: &str,
+    caldav_event: &EventWithCaldavUid,
+    state: Option<&Mutex<StateStore>>,
+) -> anyhow::Result<()> {
+    let event = &caldav_event.event;
+    let calendar_id = config
+        .google_calendar_ids
+        .first()
+        .with_context(|| "No Google calendar to push to")?;
+    debug!("Pushing event {} to {}", describe_event(event), calendar_id);
+
+    let google_time = |time: EventTime| match time {
+        EventTime::Timed(datetime) => google_calendar3::api::EventDateTime {
+            date_time: Some(datetime),
+            ..Default::default()
+        },
+        EventTime::AllDay(date) => google_calendar3::api::EventDateTime {
+            date: Some(date),
+            ..Default::default()
+        },
+    };
+    let google_event = google_calendar3::api::Event {
+        summary: Some(event.summary.clone()),
+        description: event.description.clone(),
+        location: event.location.clone(),
+        start: Some(google_time(event.start)),
+        end: Some(google_time(event.end)),
+        recurrence: event
+            .rrule
+            .as_ref()
+            .map(|rrule| vec![format!("RRULE:{}", rrule)]),
+        transparency: Some(
+            if event.transparent {
+                "transparent"
+            } else {
+                "opaque"
+            }
+            .to_string(),
+        ),
+        ..Default::default()
+    };
+    let (_, created) = with_google_timeout(
+        config,
+        None,
+        hub.events().insert(google_event, calendar_id).doit(),
+    )
+    .await
+    .with_context(|| format!("Failed to push event {}", describe_event(event)))?;
+
+    let mut mirrored = event.clone();
+    mirrored.google_id = created.id;
+    mirrored.calendar_id = Some(calendar_id.clone());
+    // It's in Google either way, and a copy too large to mirror would be skipped as it's pulled
+    update_caldav_event(config, agent, caldav_url, caldav_event, &mirrored, state).await?;
+    Ok(())
+}
+
+/// What became of a batch of changes.
+#[derive(Debug, Default)]
+struct Applied {
+    written: usize,
+    skipped: usize,
+    errors: Vec<String>,
+}
+
+/// Runs up to `caldav_concurrency` changes at a time, logging failures in the order the changes
+/// were given.
+async fn apply_changes<F, T>(
+    config: &Config,
+    changes: impl Iterator<Item = F>,
+    written: &AtomicU64,
+) -> Applied
+where
+    F: Future<Output = anyhow::Result<T>>,
+    T: Into<WriteOutcome>,
+{
+    let mut applied = Applied::default();
+    let mut results = futures::stream::iter(changes).buffered(config.caldav_concurrency);
+    while let Some(result) = results.next().await {
+        match result.map(Into::into) {
+            Ok(WriteOutcome::Written) => {
+                written.fetch_add(1, Ordering::Relaxed);
+                applied.written += 1;
+            }
+            Ok(WriteOutcome::Skipped) => applied.skipped += 1,
+            Err(e) => {
+                error!("{:#}", e);
+                applied.errors.push(format!("{:#}", e));
+            }
+        }
+    }
+    applied
+}
+
+async fn sync(config: &Config, hub: &GoogleHub, agent: &ureq::Agent) -> anyhow::Result<SyncReport> {
+    let now = chrono::Utc::now();
+    let started = std::time::Instant::now();
+    info!("Starting sync at {}", now);
+
+    let caldav_url = &config.caldav_uri;
+
+    let calendar_ids = &config.google_calendar_ids;
+
+    // The tokens are taken before fetching, so that changes made during the sync show up next time
+    let mut sync_tokens = None;
+    if config.google_sync_tokens {
+        let path = sync_tokens_path(config);
+        let previous = load_sync_tokens(&path).unwrap_or_else(|e| {
+            warn!("Ignoring sync tokens: {:#}", e);
+            None
+        });
+        match refresh_sync_tokens(config, hub, now, previous.as_ref()).await {
+            // New CalDAV events still have to be pushed, however quiet Google has been. The old
+            // tokens are kept, along with when the last full sync was.
+            Ok((_, true)) if config.sync_direction == SyncDirection::Pull => {
+                info!("No Google changes since the last full sync, skipping");
+                return Ok(SyncReport {
+                    duration: started.elapsed(),
+                    ..SyncReport::default()
+                });
+            }
+            Ok((fresh, _)) => sync_tokens = Some((path, fresh)),
+            Err(e) => warn!("Unable to check Google for changes: {:#}", e),
+        }
+    }
+
+    let collection_url = calendar_collection_url(config, agent).await;
+
+    let fetched = if config.caldav_sync_collection {
+        match fetch_caldav_changes(config, agent, &collection_url).await {
+            Ok(caldav_events) => Ok(caldav_events),
+            Err(e) => {
+                warn!(
+                    "Incremental CalDAV fetch failed, fetching the sync window instead: {:#}",
+                    e
+                );
+                fetch_caldav_events(config, agent, &collection_url, now).await
+            }
+        }
+    } else {
+        fetch_caldav_events(config, agent, &collection_url, now).await
+    };
+    let mut caldav_events = match fetched {
+        Ok(caldav_events) => caldav_events,
+        Err(e) => {
+            warn!(
+                "Calendar query failed, fetching CALDAV_URI as an iCal export instead: {:#}",
+                e
+            );
+            fetch_caldav_export(config, agent, caldav_url).await?
+        }
+    };
+    let state = match &config.state_db {
+        Some(path) => Some(Mutex::new(StateStore::open(path)?)),
+        None => None,
+    };
+    let state = state.as_ref();
+    if let Some(state) = state {
+        state
+            .lock()
+            .unwrap()
+            .identify(&mut caldav_events)
+            .with_context(|| "Failed to look up events in state database")?;
+    }
+
+    // Leave alone events mirrored from calendars we're no longer configured to sync
+    caldav_events.retain(|e| match &e.event.calendar_id {
+        Some(calendar_id) => calendar_ids.contains(calendar_id),
+        None => true,
+    });
+
+    // Google only lists events overlapping the sync window, so anything outside it isn't ours to
+    // delete. Recurring events may have occurrences in the window whatever their first one's time.
+    let (time_min, time_max) = config.sync_window(now);
+    caldav_events.retain(|e| {
+        e.event.rrule.is_some()
+            || (e.event.end.start_instant() > time_min && e.event.start.start_instant() < time_max)
+    });
+
+    let mut google_events = fetch_google_events(config, hub).await?;
+    let duplicates = dedupe_events(&mut google_events);
+    if duplicates > 0 {
+        info!("Collapsed {} duplicate Google events", duplicates);
+    }
+    let fetch_time = started.elapsed();
+    let mut diff = find_diff(&caldav_events, &google_events);
+    let to_push = match config.sync_direction {
+        SyncDirection::Pull => Vec::new(),
+        SyncDirection::Push | SyncDirection::Both => {
+            find_push(&caldav_events, &google_events, &diff)
+        }
+    };
+    if config.sync_direction == SyncDirection::Push {
+        diff = EventDiff::default();
+    }
+    if let Some(state) = state {
+        let state = state.lock().unwrap();
+        diff.to_update.retain(|(caldav_event, event)| {
+            let written = caldav_event_url(&collection_url, caldav_event)
+                .and_then(|href| state.is_written(&href, event));
+            !written.unwrap_or(false)
+        });
+    }
+    if config.apply_order == ApplyOrder::UpdateInPlace {
+        update_in_place(&mut diff);
+    }
+
+    info!(
+        "{} events to delete, {} events to update, {} events to create",
+        diff.to_delete.len(),
+        diff.to_update.len(),
+        diff.to_create.len()
+    );
+    if config.sync_direction != SyncDirection::Pull {
+        info!("{} events to push to Google", to_push.len());
+    }
+
+    if config.show_diff_summary {
+        print_diff_summary(&diff);
+    }
+
+    if let Some(path) = &config.export_diff_path {
+        if let Err(e) = export_diff(path, now, &diff) {
+            warn!("Unable to export diff: {:#}", e);
+        }
+    }
+
+    let mut report = SyncReport {
+        google_events: google_events.len(),
+        caldav_events: caldav_events.len(),
+        planned: DiffCounts {
+            to_delete: diff.to_delete.len(),
+            to_update: diff.to_update.len(),
+            to_create: diff.to_create.len(),
+            to_push: to_push.len(),
+        },
+        ..SyncReport::default()
+    };
+    if config.diff_only {
+        report.duration = started.elapsed();
+        return Ok(report);
+    }
+
+    if config.dry_run {
+        info!("DRY RUN — no changes made");
+        for event in &diff.to_delete {
+            info!("Would delete event {}", describe_event(&event.event));
+        }
+        for (caldav_event, event) in &diff.to_update {
+            info!(
+                "Would update event {} to {}",
+                describe_event(&caldav_event.event),
+                describe_event(event)
+            );
+        }
+        for event in &diff.to_create {
+            info!("Would create event {}", describe_event(event));
+        }
+        for event in &to_push {
+            info!(
+                "Would push event {} to Google",
+                describe_event(&event.event)
+            );
+        }
+        report.duration = started.elapsed();
+        return Ok(report);
+    }
+
+    check_deletion_cap(config, &diff, &caldav_events)?;
+    detect_churn(&collection_url, &diff);
+    let diff_time = started.elapsed() - fetch_time;
+
+    let deletes = apply_changes(
+        config,
+        diff.to_delete
+            .iter()
+            .map(|event| delete_caldav_event(config, agent, &collection_url, event, state)),
+        &METRICS.events_deleted,
+    );
+    let updates = apply_changes(
+        config,
+        diff.to_update.iter().map(|(caldav_event, event)| {
+            update_caldav_event(config, agent, &collection_url, caldav_event, event, state)
+        }),
+        &METRICS.events_updated,
+    );
+    let creates = apply_changes(
+        config,
+        diff.to_create
+            .iter()
+            .map(|event| create_caldav_event(config, agent, &collection_url, event, state)),
+        &METRICS.events_created,
+    );
+    let (deleted, updated, created) = match config.apply_order {
+        ApplyOrder::DeleteFirst | ApplyOrder::UpdateInPlace => {
+            let deleted = deletes.await;
+            (deleted, updates.await, creates.await)
+        }
+        ApplyOrder::CreateFirst => {
+            let created = creates.await;
+            let updated = updates.await;
+            (deletes.await, updated, created)
+        }
+    };
+    report.deleted = deleted.written;
+    report.updated = updated.written;
+    report.created = created.written;
+    report.skipped = updated.skipped + created.skipped;
+    report.errors.extend(deleted.errors);
+    report.errors.extend(updated.errors);
+    report.errors.extend(created.errors);
+
+    let pushed = apply_changes(
+        config,
+        to_push.iter().map(|caldav_event| {
+            push_caldav_event(config, hub, agent, &collection_url, caldav_event, state)
+        }),
+        &METRICS.events_pushed,
+    )
+    .await;
+    report.pushed = pushed.written;
+    report.errors.extend(pushed.errors);
+
+    report.unchanged = google_events
+        .len()
+        .saturating_sub(diff.to_create.len() + diff.to_update.len());
+    report.duration = started.elapsed();
+    debug!(
+        "Fetching took {:.1?}, diffing {:.1?}, applying {:.1?}",
+        fetch_time,
+        diff_time,
+        report.duration - fetch_time - diff_time
+    );
+
+    // Changes that failed have to be retried, even if Google doesn't change again
+    if report.errors.is_empty() {
+        if let Some((path, sync_tokens)) = sync_tokens {
+            if let Err(e) = save_sync_tokens(&path, &sync_tokens) {
+                warn!("Unable to save Google sync tokens: {:#}", e);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Checks that Google and the CalDAV server can both be reached and authenticated with, printing
+/// OK or FAIL for each, without syncing anything.
+pub async fn healthcheck(config: &Config) -> anyhow::Result<()> {
+    let google = async {
+        let hub = google_hub(config).await?;
+        for calendar_id in &config.google_calendar_ids {
+            with_google_timeout(config, None, hub.calendars().get(calendar_id).doit())
+                .await
+                .with_context(|| format!("Failed to get Google calendar {}", calendar_id))?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    let caldav = async {
+        let agent = caldav_agent(config)?;
+        for target in config.sync_targets() {
+            let url = Url::parse(&target.caldav_uri)
+                .with_context(|| format!("Invalid CALDAV_URI {}", target.caldav_uri))?;
+            propfind_dav_resources(config, &agent, &url, "0").await?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    let mut healthy = true;
+    for (name, result) in [("Google", google), ("CalDAV", caldav)] {
+        match result {
+            Ok(()) => println!("{}: OK", name),
+            Err(e) => {
+                println!("{}: FAIL ({:#})", name, e);
+                healthy = false;
+            }
+        }
+    }
+    if !healthy {
+        anyhow::bail!("Health check failed");
+    }
+    Ok(())
+}
+
+/// Deletes every event we wrote to the CalDAV calendar, whatever its time, leaving any others
+/// alone. As this can't be undone, it needs `--yes`, or confirmation at a terminal.
+pub async fn purge(config: &Config) -> anyhow::Result<()> {
+    for target in config.sync_targets() {
+        purge_collection(&target).await?;
+    }
+    Ok(())
+}
+
+async fn purge_collection(config: &Config) -> anyhow::Result<()> {
+    let agent = caldav_agent(config)?;
+    let collection_url = calendar_collection_url(config, &agent).await;
+    let caldav_events = query_caldav_events(config, &agent, &collection_url, None).await?;
+    let (to_delete, unmanaged): (Vec<_>, Vec<_>) = caldav_events.iter().partition(|e| e.managed);
+    println!(
+        "{} events to purge from {}, {} events not written by us left alone",
+        to_delete.len(),
+        collection_url,
+        unmanaged.len()
+    );
+    if to_delete.is_empty() {
+        return Ok(());
+    }
+    if config.dry_run {
+        for event in &to_delete {
+            println!("Would delete event {}", describe_event(&event.event));
+        }
+        return Ok(());
+    }
+
+    if !config.assume_yes {
+        if !std::io::stdin().is_terminal() {
+            anyhow::bail!("Refusing to purge without --yes");
+        }
+        print!("Delete {} events? [y/N] ", to_delete.len());
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            anyhow::bail!("Purge cancelled");
+        }
+    }
+
+    let state = match &config.state_db {
+        Some(path) => Some(Mutex::new(StateStore::open(path)?)),
+        None => None,
+    };
+    let state = state.as_ref();
+    let failures = apply_changes(
+        config,
+        to_delete
+            .iter()
+            .map(|event| delete_caldav_event(config, &agent, &collection_url, event, state)),
+        &METRICS.events_deleted,
+    )
+    .await
+    .errors
+    .len();
+    println!(
+        "Purged {} of {} events",
+        to_delete.len() - failures,
+        to_delete.len()
+    );
+    if failures > 0 {
+        anyhow::bail!("{} of {} deletions failed", failures, to_delete.len());
+    }
+    Ok(())
+}
+
+/// Takes an exclusive lock on the PID file and writes our process id to it, failing if another
+/// instance holds the lock. The lock lasts as long as the returned file is open, so it's released
+/// even if we crash. Locking is only supported on unix; elsewhere the PID is just written.
+pub fn lock_pid_file(path: &Path) -> anyhow::Result<std::fs::File> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("Failed to open PID file {}", path.display()))?;
+    #[cfg(unix)]
+    lock_file(&mut file, path)?;
+    #[cfg(not(unix))]
+    warn!("PID file locking isn't supported on this platform, so other instances aren't kept out");
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())
+        .with_context(|| format!("Failed to write PID file {}", path.display()))?;
+    Ok(file)
+}
+
+#[cfg(unix)]
+fn lock_file(file: &mut std::fs::File, path: &Path) -> anyhow::Result<()> {
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(());
+    }
+    let error = std::io::Error::last_os_error();
+    if error.kind() == std::io::ErrorKind::WouldBlock {
+        let mut pid = String::new();
+        file.read_to_string(&mut pid).ok();
+        let holder = match pid.trim() {
+            "" => String::new(),
+            pid => format!(" as PID {}", pid),
+        };
+        anyhow::bail!(
+            "Another instance is already running{}, holding the lock on {}",
+            holder,
+            path.display()
+        );
+    }
+    Err(error).with_context(|| format!("Failed to lock PID file {}", path.display()))
+}
+
+/// Syncs each Google calendar with its own collection, or all of them with CALDAV_URI. One failing
+/// doesn't stop the others from syncing.
+async fn sync_targets(
+    config: &Config,
+    hub: &GoogleHub,
+    agent: &ureq::Agent,
+) -> anyhow::Result<SyncReport> {
+    let targets = config.sync_targets();
+    if let [target] = targets.as_slice() {
+        return sync(target, hub, agent).await;
+    }
+
+    let mut total = SyncReport::default();
+    let mut failures = Vec::new();
+    for target in &targets {
+        let calendar_id = target.google_calendar_ids.join(",");
+        info!(
+            "Syncing Google calendar {} into {}",
+            calendar_id, target.caldav_uri
+        );
+        match sync(target, hub, agent).await {
+            Ok(report) => {
+                info!("Google calendar {}: {}", calendar_id, report);
+                total.absorb(report);
+            }
+            Err(e) => {
+                error!("Sync of Google calendar {} failed: {:#}", calendar_id, e);
+                failures.push(e);
+            }
+        }
+    }
+    let failed = failures.len();
+    match failures.into_iter().next() {
+        Some(e) => Err(e.context(format!(
+            "{} of {} calendars failed to sync",
+            failed,
+            targets.len()
+        ))),
+        None => Ok(total),
+    }
+}
+
+/// The window the next step of a backfill should sync, if any collection has yet to be backfilled
+/// over the whole window. Each step is one sync, widening the window by BACKFILL_STEP_DAYS past
+/// the narrowest collection's progress, so a first sync is spread out over several intervals.
+fn next_backfill_step(
+    config: &Config,
+    state_db: &Path,
+    step: chrono::TimeDelta,
+) -> anyhow::Result<Option<Config>> {
+    let state = StateStore::open(state_db)?;
+    let mut done = i64::MAX;
+    for target in config.sync_targets() {
+        done = done.min(state.backfilled_days(&target.caldav_uri)?);
+    }
+    let done = chrono::TimeDelta::days(done);
+    if done >= config.window_past.max(config.window_future) {
+        return Ok(None);
+    }
+    let width = done + step;
+    // Sync tokens would skip each wider window as nothing having changed
+    Ok(Some(Config {
+        window_past: width.min(config.window_past),
+        window_future: width.min(config.window_future),
+        google_sync_tokens: false,
+        ..config.clone()
+    }))
+}
+
+fn record_backfill_step(config: &Config, window: &Config, state_db: &Path) -> anyhow::Result<()> {
+    let state = StateStore::open(state_db)?;
+    let days = window.window_past.max(window.window_future).num_days();
+    for target in config.sync_targets() {
+        if state.backfilled_days(&target.caldav_uri)? < days {
+            state.record_backfilled_days(&target.caldav_uri, days)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs a single sync, recording its outcome in the metrics.
+pub async fn run_sync(
+    config: &Config,
+    hub: &GoogleHub,
+    agent: &ureq::Agent,
+) -> anyhow::Result<SyncReport> {
+    let backfill_step = match (config.backfill_step, &config.state_db) {
+        (Some(step), Some(state_db)) if !config.dry_run && !config.diff_only => {
+            next_backfill_step(config, state_db, step)?.map(|window| (window, state_db))
+        }
+        _ => None,
+    };
+    let result = match backfill_step {
+        Some((window, state_db)) => {
+            info!(
+                "Backfilling {} days back and {} days ahead",
+                window.window_past.num_days(),
+                window.window_future.num_days()
+            );
+            let result = sync_targets(&window, hub, agent).await;
+            // A step with errors is retried next time, rather than widening past what it missed
+            if let Ok(report) = &result {
+                if report.errors.is_empty() {
+                    if let Err(e) = record_backfill_step(config, &window, state_db) {
+                        warn!("Unable to record the backfill's progress: {:#}", e);
+                    }
+                }
+            }
+            result
+        }
+        None => sync_targets(config, hub, agent).await,
+    };
+    METRICS.sync_runs.fetch_add(1, Ordering::Relaxed);
+    match &result {
+        Ok(report) if report.errors.is_empty() => METRICS
+            .last_successful_sync
+            .store(Utc::now().timestamp(), Ordering::Relaxed),
+        _ => {
+            METRICS.sync_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(summary: &str, start: &str, end: &str) -> Event {
+        Event {
+            google_id: None,
+            calendar_id: None,
+            start: EventTime::Timed(start.parse().unwrap()),
+            end: EventTime::Timed(end.parse().unwrap()),
+            summary: summary.to_string(),
+            description: None,
+            location: None,
+            rrule: None,
+            exdates: Vec::new(),
+            rdates: Vec::new(),
+            transparent: false,
+            organizer: None,
+            organizer_name: None,
+            color: None,
+            time_zone: None,
+            attendee_count: None,
+            private: false,
+            created: None,
+            last_modified: None,
+        }
+    }
+
+    /// Configuration for syncing primary into a made-up collection, with the given variables set.
+    fn config(vars: &[(&str, &str)]) -> Config {
+        let env = [
+            ("CALDAV_URI", "https://example.com/cal/"),
+            ("GOOGLE_CALENDAR_ID", "primary"),
+        ]
+        .iter()
+        .chain(vars)
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        load_config_from(Args::default(), env).unwrap()
+    }
+
+    fn google_time(datetime: &str) -> google_calendar3::api::EventDateTime {
+        google_calendar3::api::EventDateTime {
+            date_time: Some(datetime.parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    fn caldav_event(uid: &str, event: Event) -> EventWithCaldavUid {
+        EventWithCaldavUid {
+            caldav_uid: uid.to_string(),
+            href: None,
+            etag: None,
+            managed: true,
+            event,
+        }
+    }
+
+    fn with_google_id(mut event: Event, google_id: &str) -> Event {
+        event.google_id = Some(google_id.to_string());
+        event.calendar_id = Some("primary".to_string());
+        event
+    }
+
+    fn deleted_uids<'a>(diff: &EventDiff<'a>) -> Vec<&'a str> {
+        diff.to_delete
+            .iter()
+            .map(|e| e.caldav_uid.as_str())
+            .collect()
+    }
+
+    fn created_summaries<'a>(diff: &EventDiff<'a>) -> Vec<&'a str> {
+        diff.to_create.iter().map(|e| e.summary.as_str()).collect()
+    }
+
+    fn utc(datetime: &str) -> DateTime<Utc> {
+        datetime.parse().unwrap()
+    }
+
+    /// Each component of a VTIMEZONE, as its name and the values of its properties.
+    fn vtimezone_components(tz: Tz) -> Vec<(String, Vec<(String, String)>)> {
+        vtimezone(tz, 2024)
+            .children
+            .iter()
+            .map(|component| {
+                let properties = component
+                    .properties
+                    .iter()
+                    .map(|p| (p.name.clone(), p.value.clone()))
+                    .collect();
+                (component.name.clone(), properties)
+            })
+            .collect()
+    }
+
+    fn component(name: &str, properties: &[(&str, &str)]) -> (String, Vec<(String, String)>) {
+        let properties = properties
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        (name.to_string(), properties)
+    }
+
+    #[test]
+    fn only_413_and_max_resource_size_count_as_too_large() {
+        assert!(is_too_large_response(413, ""));
+        assert!(is_too_large_response(
+            403,
+            r#"<?xml version="1.0"?><d:error xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav"><c:max-resource-size/></d:error>"#
+        ));
+        assert!(!is_too_large_response(
+            400,
+            "DTEND exceeds the maximum date"
+        ));
+        assert!(!is_too_large_response(
+            403,
+            r#"<d:error xmlns:d="DAV:"><d:need-privileges/></d:error>"#
+        ));
+    }
+
+    #[test]
+    fn backfill_widens_one_recorded_step_at_a_time() {
+        let state_db = std::env::temp_dir().join(format!("backfill-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&state_db);
+        let state_db_var = state_db.to_str().unwrap();
+        let config = config(&[
+            ("WINDOW_PAST_DAYS", "10"),
+            ("WINDOW_FUTURE_DAYS", "25"),
+            ("BACKFILL_STEP_DAYS", "10"),
+            ("STATE_DB", state_db_var),
+        ]);
+        let step = config.backfill_step.unwrap();
+        let mut widths = Vec::new();
+        while let Some(window) = next_backfill_step(&config, &state_db, step).unwrap() {
+            widths.push((
+                window.window_past.num_days(),
+                window.window_future.num_days(),
+            ));
+            assert!(!window.google_sync_tokens);
+            record_backfill_step(&config, &window, &state_db).unwrap();
+        }
+        std::fs::remove_file(&state_db).unwrap();
+        assert_eq!(widths, vec![(10, 10), (10, 20), (10, 25)]);
+    }
+
+    #[test]
+    fn busy_merge_hides_recurring_events_details() {
+        let config = config(&[("BUSY_MERGE", "true"), ("SYNC_RECURRENCE", "true")]);
+        assert!(!config.sync_recurrence);
+
+        let mut recurring = event(
+            "1:1 with Jane",
+            "2024-03-04T09:00:00Z",
+            "2024-03-04T09:30:00Z",
+        );
+        recurring.google_id = Some("one-on-one".to_string());
+        recurring.description = Some("Career chat".to_string());
+        recurring.location = Some("Room 4".to_string());
+        recurring.organizer = Some("jane@example.com".to_string());
+        recurring.color = Some("tomato".to_string());
+        recurring.rrule = Some("FREQ=WEEKLY".to_string());
+        recurring.exdates = vec![EventTime::Timed(utc("2024-03-11T09:00:00Z"))];
+        let merged = merge_busy_events(
+            &config,
+            vec![
+                event("Standup", "2024-03-04T08:00:00Z", "2024-03-04T08:15:00Z"),
+                recurring,
+            ],
+        );
+
+        assert_eq!(merged.len(), 2);
+        let mut expected = event("Busy", "2024-03-04T09:00:00Z", "2024-03-04T09:30:00Z");
+        expected.rrule = Some("FREQ=WEEKLY".to_string());
+        expected.exdates = vec![EventTime::Timed(utc("2024-03-11T09:00:00Z"))];
+        assert_eq!(merged[1], expected);
+        assert_eq!(merged[1].google_id, None);
+    }
+
+    #[test]
+    fn attendees_are_only_counted_when_google_lists_them_all() {
+        let config = config(&[("SYNC_ATTENDEE_COUNT", "true")]);
+        let attendee = |email: &str| google_calendar3::api::EventAttendee {
+            email: Some(email.to_string()),
+            response_status: Some("accepted".to_string()),
+            ..Default::default()
+        };
+        let meeting = |id: &str, attendees_omitted| google_calendar3::api::Event {
+            id: Some(id.to_string()),
+            summary: Some("Meeting".to_string()),
+            start: Some(google_time("2024-03-04T09:00:00Z")),
+            end: Some(google_time("2024-03-04T10:00:00Z")),
+            attendees: Some(match attendees_omitted {
+                Some(true) => vec![attendee("me@example.com")],
+                _ => vec![attendee("me@example.com"), attendee("you@example.com")],
+            }),
+            attendees_omitted,
+            ..Default::default()
+        };
+
+        let events = mirror_google_events(
+            &config,
+            "primary",
+            &[meeting("small", None), meeting("all-hands", Some(true))],
+        );
+        assert_eq!(events[0].attendee_count, Some(2));
+        assert_eq!(events[1].attendee_count, None);
+    }
+
+    #[test]
+    fn modified_occurrences_are_excluded_from_their_master_and_mirrored_alone() {
+        let config = config(&[("SYNC_RECURRENCE", "true")]);
+        let time = google_time;
+        let google_events = [
+            google_calendar3::api::Event {
+                id: Some("standup".to_string()),
+                summary: Some("Standup".to_string()),
+                start: Some(time("2024-03-04T09:00:00Z")),
+                end: Some(time("2024-03-04T09:15:00Z")),
+                recurrence: Some(vec!["RRULE:FREQ=DAILY;COUNT=5".to_string()]),
+                ..Default::default()
+            },
+            google_calendar3::api::Event {
+                id: Some("standup_20240306T090000Z".to_string()),
+                recurring_event_id: Some("standup".to_string()),
+                original_start_time: Some(time("2024-03-06T09:00:00Z")),
+                summary: Some("Standup (moved)".to_string()),
+                start: Some(time("2024-03-06T11:00:00Z")),
+                end: Some(time("2024-03-06T11:15:00Z")),
+                ..Default::default()
+            },
+            google_calendar3::api::Event {
+                id: Some("standup_20240305T090000Z".to_string()),
+                recurring_event_id: Some("standup".to_string()),
+                original_start_time: Some(time("2024-03-05T09:00:00Z")),
+                status: Some("cancelled".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        let events = mirror_google_events(&config, "primary", &google_events);
+        assert_eq!(events.len(), 2);
+        let master = &events[0];
+        assert_eq!(master.rrule.as_deref(), Some("FREQ=DAILY;COUNT=5"));
+        assert_eq!(
+            master.exdates,
+            vec![
+                EventTime::Timed(utc("2024-03-05T09:00:00Z")),
+                EventTime::Timed(utc("2024-03-06T09:00:00Z")),
+            ]
+        );
+        let moved = &events[1];
+        assert_eq!(moved.google_id.as_deref(), Some("standup_20240306T090000Z"));
+        assert_eq!(moved.summary, "Standup (moved)");
+        assert_eq!(moved.start, EventTime::Timed(utc("2024-03-06T11:00:00Z")));
+        assert_eq!(moved.rrule, None);
+    }
+
+    #[test]
+    fn recurrence_exdates_and_rdates_are_kept() {
+        let config = config(&[("SYNC_RECURRENCE", "true")]);
+        let mut start = google_time("2026-01-05T09:00:00+01:00");
+        start.time_zone = Some("Europe/Berlin".to_string());
+        let google_events = [google_calendar3::api::Event {
+            id: Some("weekly".to_string()),
+            summary: Some("Weekly".to_string()),
+            start: Some(start),
+            end: Some(google_time("2026-01-05T10:00:00+01:00")),
+            recurrence: Some(vec![
+                "RRULE:FREQ=WEEKLY;COUNT=4".to_string(),
+                "EXDATE;TZID=Europe/Berlin:20260112T090000,20260119T090000".to_string(),
+                "RDATE:20260107T080000Z".to_string(),
+            ]),
+            ..Default::default()
+        }];
+
+        let events = mirror_google_events(&config, "primary", &google_events);
+        assert_eq!(events[0].rrule.as_deref(), Some("FREQ=WEEKLY;COUNT=4"));
+        assert_eq!(
+            events[0].exdates,
+            vec![
+                EventTime::Timed(utc("2026-01-12T08:00:00Z")),
+                EventTime::Timed(utc("2026-01-19T08:00:00Z")),
+            ]
+        );
+        assert_eq!(
+            events[0].rdates,
+            vec![EventTime::Timed(utc("2026-01-07T08:00:00Z"))]
+        );
+    }
+
+    #[test]
+    fn transport_errors_are_only_retried_when_repeatable() {
+        // Nothing listens on port 1, so connecting fails
+        let transport = ureq::get("http://127.0.0.1:1/").call().unwrap_err();
+        assert!(is_retryable_caldav_error(&transport, true));
+        assert!(!is_retryable_caldav_error(&transport, false));
+        let unavailable = ureq::Error::Status(503, ureq::Response::new(503, "", "").unwrap());
+        assert!(is_retryable_caldav_error(&unavailable, false));
+    }
+
+    #[test]
+    fn retry_backoff_is_capped() {
+        assert!(retry_backoff(0) >= Duration::from_millis(500));
+        for attempt in [20, 64, u32::MAX] {
+            assert_eq!(retry_backoff(attempt), MAX_RETRY_BACKOFF);
+        }
+    }
+
+    #[test]
+    fn check_args_rejects_unknown_flags_and_missing_values() {
+        let args =
+            |args: &[&str]| check_args(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>());
+        assert!(args(&["--once", "--dry-run", "-vv", "--interval", "30"]).is_ok());
+        assert!(args(&[
+            "--caldav-uri=https://example.com/cal/",
+            "--config",
+            "a.toml"
+        ])
+        .is_ok());
+        assert!(args(&["--dryrun"]).is_err());
+        assert!(args(&["--dry_run"]).is_err());
+        assert!(args(&["--once=true"]).is_err());
+        assert!(args(&["--window-radius"]).is_err());
+        assert!(args(&["--window-radius", "--once"]).is_err());
+        assert!(args(&["--interval="]).is_err());
+    }
+
+    #[test]
+    fn check_args_parses_flags_for_the_config() {
+        let args = |args: &[&str]| {
+            check_args(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>()).unwrap()
+        };
+        let parsed = args(&["-vv", "--once", "--config=a.toml", "--verbose"]);
+        assert!(parsed.has("--once"));
+        assert!(!parsed.has("--dry-run"));
+        assert_eq!(parsed.verbosity(None), 3);
+        assert_eq!(parsed.verbosity(Some("5")), 5);
+        assert_eq!(parsed.config_file, Some(PathBuf::from("a.toml")));
+
+        let env = HashMap::from([
+            (
+                "CALDAV_URI".to_string(),
+                "https://example.com/cal/".to_string(),
+            ),
+            ("GOOGLE_CALENDAR_ID".to_string(), "primary".to_string()),
+            ("SYNC_INTERVAL".to_string(), "10".to_string()),
+        ]);
+        let config = load_config_from(args(&["--interval", "30", "--interval=45"]), env).unwrap();
+        assert_eq!(config.sync_interval, Duration::from_secs(45));
+    }
+
+    #[test]
+    fn join_caldav_path_handles_bases_with_and_without_trailing_slash() {
+        for base in [
+            "https://dav.example.com/cal/",
+            "https://dav.example.com/cal",
+        ] {
+            assert_eq!(
+                join_caldav_path(base, "abc.ics").unwrap(),
+                "https://dav.example.com/cal/abc.ics"
+            );
+        }
+        assert_eq!(
+            join_caldav_path("https://dav.example.com", "abc.ics").unwrap(),
+            "https://dav.example.com/abc.ics"
+        );
+        assert_eq!(
+            join_caldav_path("https://dav.example.com/a/cal/?x=1", "abc.ics").unwrap(),
+            "https://dav.example.com/a/cal/abc.ics?x=1"
+        );
+    }
+
+    #[test]
+    fn join_caldav_path_percent_encodes_the_name() {
+        assert_eq!(
+            join_caldav_path("https://dav.example.com/cal/", "a b/c%d#e?.ics").unwrap(),
+            "https://dav.example.com/cal/a%20b%2Fc%25d%23e%3F.ics"
+        );
+    }
+
+    #[test]
+    fn caldav_event_url_percent_encodes_reserved_characters_in_uid() {
+        let caldav_event = caldav_event(
+            "team/standup@example.com 1",
+            event("Standup", "2024-03-01T09:00:00Z", "2024-03-01T09:15:00Z"),
+        );
+        assert_eq!(
+            caldav_event_url("https://dav.example.com/cal/", &caldav_event).unwrap(),
+            "https://dav.example.com/cal/team%2Fstandup%40example.com%201.ics"
+        );
+    }
+
+    #[test]
+    fn content_hash_only_covers_newer_fields_when_set() {
+        let mut event = event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z");
+        let original_fields = (
+            event.start,
+            event.end,
+            &event.summary,
+            &event.description,
+            &event.location,
+            &event.rrule,
+            &event.exdates,
+            event.transparent,
+            &event.organizer,
+            &event.organizer_name,
+            &event.color,
+        );
+        let original_hash = md5_hex(&format!("{:?}", original_fields));
+        assert_eq!(StateStore::content_hash(&event), original_hash);
+
+        event.time_zone = Some(Tz::Europe__Berlin);
+        assert_ne!(StateStore::content_hash(&event), original_hash);
+    }
+
+    #[test]
+    fn find_diff_of_identical_sets_is_empty() {
+        let current = vec![
+            caldav_event(
+                "a",
+                event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+            ),
+            caldav_event(
+                "b",
+                with_google_id(
+                    event("Two", "2024-03-02T09:00:00Z", "2024-03-02T10:00:00Z"),
+                    "g2",
+                ),
+            ),
+        ];
+        let target = vec![
+            event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+            with_google_id(
+                event("Two", "2024-03-02T09:00:00Z", "2024-03-02T10:00:00Z"),
+                "g2",
+            ),
+        ];
+
+        let diff = find_diff(&current, &target);
+        assert!(diff.to_delete.is_empty());
+        assert!(diff.to_update.is_empty());
+        assert!(diff.to_create.is_empty());
+    }
+
+    #[test]
+    fn find_diff_creates_added_events() {
+        let current = vec![caldav_event(
+            "a",
+            event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+        )];
+        let target = vec![
+            event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+            event("Two", "2024-03-02T09:00:00Z", "2024-03-02T10:00:00Z"),
+        ];
+
+        let diff = find_diff(&current, &target);
+        assert!(diff.to_delete.is_empty());
+        assert!(diff.to_update.is_empty());
+        assert_eq!(created_summaries(&diff), vec!["Two"]);
+    }
+
+    #[test]
+    fn find_diff_deletes_removed_events() {
+        let current = vec![
+            caldav_event(
+                "a",
+                event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+            ),
+            caldav_event(
+                "b",
+                event("Two", "2024-03-02T09:00:00Z", "2024-03-02T10:00:00Z"),
+            ),
+        ];
+        let target = vec![event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z")];
+
+        let diff = find_diff(&current, &target);
+        assert_eq!(deleted_uids(&diff), vec!["b"]);
+        assert!(diff.to_update.is_empty());
+        assert!(diff.to_create.is_empty());
+    }
+
+    #[test]
+    fn find_diff_leaves_unmanaged_events_alone() {
+        let mut unmanaged = caldav_event(
+            "b",
+            event("Mine", "2024-03-02T09:00:00Z", "2024-03-02T10:00:00Z"),
+        );
+        unmanaged.managed = false;
+        let current = vec![
+            caldav_event(
+                "a",
+                event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+            ),
+            unmanaged,
+        ];
+
+        let diff = find_diff(&current, &[]);
+        assert_eq!(deleted_uids(&diff), vec!["a"]);
+    }
+
+    #[test]
+    fn find_diff_does_not_adopt_unmanaged_copies() {
+        let copy = event("Mine", "2024-03-02T09:00:00Z", "2024-03-02T10:00:00Z");
+        let mut unmanaged = caldav_event("b", copy.clone());
+        unmanaged.managed = false;
+        let current = vec![unmanaged];
+        let target = vec![copy];
+
+        let diff = find_diff(&current, &target);
+        assert!(diff.to_delete.is_empty());
+        assert!(diff.to_update.is_empty());
+        assert_eq!(diff.to_create.len(), 1);
+    }
+
+    #[test]
+    fn find_diff_replaces_legacy_event_whose_summary_changed() {
+        let current = vec![caldav_event(
+            "a",
+            event("Old", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+        )];
+        let target = vec![event("New", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z")];
+
+        let diff = find_diff(&current, &target);
+        assert_eq!(deleted_uids(&diff), vec!["a"]);
+        assert_eq!(created_summaries(&diff), vec!["New"]);
+    }
+
+    #[test]
+    fn find_diff_updates_tracked_event_whose_summary_changed() {
+        let current = vec![caldav_event(
+            "a",
+            with_google_id(
+                event("Old", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+                "g1",
+            ),
+        )];
+        let target = vec![with_google_id(
+            event("New", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+            "g1",
+        )];
+
+        let diff = find_diff(&current, &target);
+        assert!(diff.to_delete.is_empty());
+        assert!(diff.to_create.is_empty());
+        assert_eq!(diff.to_update.len(), 1);
+        assert_eq!(diff.to_update[0].0.caldav_uid, "a");
+        assert_eq!(diff.to_update[0].1.summary, "New");
+    }
+
+    #[test]
+    fn find_diff_keeps_overlapping_distinct_events_apart() {
+        let current = vec![caldav_event(
+            "a",
+            event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+        )];
+        let target = vec![
+            event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+            event("Two", "2024-03-01T09:30:00Z", "2024-03-01T10:30:00Z"),
+            event("One", "2024-03-01T09:00:00Z", "2024-03-01T11:00:00Z"),
+        ];
+
+        let diff = find_diff(&current, &target);
+        assert!(diff.to_delete.is_empty());
+        assert!(diff.to_update.is_empty());
+        assert_eq!(created_summaries(&diff), vec!["Two", "One"]);
+        assert_eq!(
+            diff.to_create[1].end,
+            EventTime::Timed("2024-03-01T11:00:00Z".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn find_diff_of_empty_inputs_is_empty() {
+        let diff = find_diff(&[], &[]);
+        assert!(diff.to_delete.is_empty());
+        assert!(diff.to_update.is_empty());
+        assert!(diff.to_create.is_empty());
+
+        let target = vec![event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z")];
+        assert_eq!(created_summaries(&find_diff(&[], &target)), vec!["One"]);
+
+        let current = vec![caldav_event(
+            "a",
+            event("One", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z"),
+        )];
+        assert_eq!(deleted_uids(&find_diff(&current, &[])), vec!["a"]);
+    }
+
+    #[test]
+    fn to_ical_round_trips_through_parse_ical() {
+        let mut original = event(
+            "Standup, daily",
+            "2024-03-01T09:00:00Z",
+            "2024-03-01T09:15:00Z",
+        );
+        original.google_id = Some("abc123".to_string());
+        original.calendar_id = Some("primary".to_string());
+        original.description = Some("Line one\nLine two; with punctuation".to_string());
+
+        let serialized = fold_ical_lines(&original.to_ical("uid-1").serialize());
+        let parsed = minicaldav::parse_ical(&serialized).unwrap();
+
+        assert_eq!(get_ical_property(&parsed, "VERSION").unwrap().value, "2.0");
+        assert_eq!(get_ical_property(&parsed, "PRODID").unwrap().value, PRODID);
+
+        let vevent = parsed
+            .children
+            .iter()
+            .find(|child| child.name == "VEVENT")
+            .unwrap();
+        let round_tripped = parse_caldav_event(vevent, Tz::UTC).unwrap();
+        assert_eq!(round_tripped.caldav_uid, "uid-1");
+        assert!(round_tripped.managed);
+        assert_eq!(round_tripped.event, original);
+        assert_eq!(round_tripped.event.google_id, original.google_id);
+        assert_eq!(round_tripped.event.calendar_id, original.calendar_id);
+    }
+
+    #[test]
+    fn to_ical_round_trips_every_field() {
+        let original = Event {
+            google_id: Some("abc123".to_string()),
+            calendar_id: Some("team@group.calendar.google.com".to_string()),
+            // Timed, as only timed events keep their zone
+            start: EventTime::Timed(utc("2024-03-01T08:00:00Z")),
+            end: EventTime::Timed(utc("2024-03-01T16:00:00Z")),
+            summary: r"Offsite: planning, budget; \ notes".to_string(),
+            description: Some("Agenda:\n\t1. Intro, \"quoted\"\n\t2. Wrap-up; done".to_string()),
+            location: Some("Room 4, Building B; Floor 2".to_string()),
+            rrule: Some("FREQ=WEEKLY;BYDAY=FR;COUNT=4".to_string()),
+            exdates: vec![
+                EventTime::Timed(utc("2024-03-08T08:00:00Z")),
+                EventTime::Timed(utc("2024-03-15T08:00:00Z")),
+            ],
+            rdates: vec![EventTime::Timed(utc("2024-03-20T08:00:00Z"))],
+            transparent: true,
+            organizer: Some("organizer@example.com".to_string()),
+            organizer_name: Some("Doe, Jane".to_string()),
+            color: Some("seagreen".to_string()),
+            time_zone: Some(Tz::Europe__Berlin),
+            attendee_count: Some(12),
+            private: true,
+            created: Some(utc("2024-01-01T12:00:00Z")),
+            last_modified: Some(utc("2024-02-01T12:30:00Z")),
+        };
+
+        let serialized = fold_ical_lines(&original.to_ical("uid-1").serialize());
+        let parsed = minicaldav::parse_ical(&serialized).unwrap();
+        let vevent = parsed
+            .children
+            .iter()
+            .find(|child| child.name == "VEVENT")
+            .unwrap();
+        let round_tripped = parse_caldav_event(vevent, Tz::UTC).unwrap().event;
+
+        assert_eq!(round_tripped, original);
+        assert_eq!(round_tripped.google_id, original.google_id);
+        assert_eq!(round_tripped.calendar_id, original.calendar_id);
+        assert_eq!(round_tripped.created, original.created);
+        assert_eq!(round_tripped.last_modified, original.last_modified);
+    }
+
+    #[test]
+    fn vtimezone_describes_nth_weekday_changes() {
+        assert_eq!(
+            vtimezone_components(Tz::America__New_York),
+            vec![
+                component(
+                    "DAYLIGHT",
+                    &[
+                        ("DTSTART", "20240310T020000"),
+                        ("TZOFFSETFROM", "-0500"),
+                        ("TZOFFSETTO", "-0400"),
+                        ("TZNAME", "EDT"),
+                        ("RRULE", "FREQ=YEARLY;BYMONTH=3;BYDAY=2SU"),
+                    ]
+                ),
+                component(
+                    "STANDARD",
+                    &[
+                        ("DTSTART", "20241103T020000"),
+                        ("TZOFFSETFROM", "-0400"),
+                        ("TZOFFSETTO", "-0500"),
+                        ("TZNAME", "EST"),
+                        ("RRULE", "FREQ=YEARLY;BYMONTH=11;BYDAY=1SU"),
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn vtimezone_describes_last_weekday_changes() {
+        assert_eq!(
+            vtimezone_components(Tz::Europe__London),
+            vec![
+                component(
+                    "DAYLIGHT",
+                    &[
+                        ("DTSTART", "20240331T010000"),
+                        ("TZOFFSETFROM", "+0000"),
+                        ("TZOFFSETTO", "+0100"),
+                        ("TZNAME", "BST"),
+                        ("RRULE", "FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU"),
+                    ]
+                ),
+                component(
+                    "STANDARD",
+                    &[
+                        ("DTSTART", "20241027T020000"),
+                        ("TZOFFSETFROM", "+0100"),
+                        ("TZOFFSETTO", "+0000"),
+                        ("TZNAME", "GMT"),
+                        ("RRULE", "FREQ=YEARLY;BYMONTH=10;BYDAY=-1SU"),
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn vtimezone_describes_fixed_offset_zones() {
+        assert_eq!(
+            vtimezone_components(Tz::Asia__Kolkata),
+            vec![component(
+                "STANDARD",
+                &[
+                    ("DTSTART", "19700101T000000"),
+                    ("TZOFFSETFROM", "+0530"),
+                    ("TZOFFSETTO", "+0530"),
+                    ("TZNAME", "IST"),
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn to_ical_round_trips_times_in_their_zone() {
+        let mut original = event("Standup", "2024-07-01T13:00:00Z", "2024-07-01T13:15:00Z");
+        original.time_zone = Some(Tz::America__New_York);
+        original.exdates = vec![EventTime::Timed(utc("2024-07-08T13:00:00Z"))];
+
+        let serialized = fold_ical_lines(&original.to_ical("uid-1").serialize());
+        for line in [
+            "DTSTART;TZID=America/New_York:20240701T090000",
+            "DTEND;TZID=America/New_York:20240701T091500",
+            "EXDATE;TZID=America/New_York:20240708T090000",
+            "TZID:America/New_York",
+        ] {
+            assert!(
+                serialized.split("\r\n").any(|l| l == line),
+                "{} missing from {}",
+                line,
+                serialized
+            );
+        }
+
+        let parsed = minicaldav::parse_ical(&serialized).unwrap();
+        let vevent = parsed
+            .children
+            .iter()
+            .find(|child| child.name == "VEVENT")
+            .unwrap();
+        let round_tripped = parse_caldav_event(vevent, Tz::UTC).unwrap().event;
+        assert_eq!(round_tripped, original);
+        assert_eq!(round_tripped.time_zone, Some(Tz::America__New_York));
+    }
+
+    #[test]
+    fn parse_ical_datetime_reads_utc_values() {
+        let property = ical::Property::new("DTSTART", "20240301T093000Z");
+        assert_eq!(
+            parse_ical_datetime(&property, Tz::UTC).unwrap(),
+            utc("2024-03-01T09:30:00Z")
+        );
+    }
+
+    #[test]
+    fn parse_ical_datetime_reads_tzid_values() {
+        let property = ical::Property::new_with_attributes(
+            "DTSTART",
+            "20240301T093000",
+            vec![("TZID", "America/New_York")],
+        );
+        assert_eq!(
+            parse_ical_datetime(&property, Tz::UTC).unwrap(),
+            utc("2024-03-01T14:30:00Z")
+        );
+    }
+
+    #[test]
+    fn parse_ical_datetime_reads_floating_values_in_default_zone() {
+        let property = ical::Property::new("DTSTART", "20240301T093000");
+        assert_eq!(
+            parse_ical_datetime(&property, Tz::UTC).unwrap(),
+            utc("2024-03-01T09:30:00Z")
+        );
+        assert_eq!(
+            parse_ical_datetime(&property, Tz::Europe__Berlin).unwrap(),
+            utc("2024-03-01T08:30:00Z")
+        );
+    }
+
+    #[test]
+    fn parse_ical_datetime_rejects_unknown_tzid() {
+        let property = ical::Property::new_with_attributes(
+            "DTSTART",
+            "20240301T093000",
+            vec![("TZID", "Not/A_Zone")],
+        );
+        assert!(parse_ical_datetime(&property, Tz::UTC).is_err());
+    }
+
+    #[test]
+    fn parse_ical_datetime_rejects_malformed_values() {
+        for value in ["", "20240301", "2024-03-01T09:30:00Z", "20241301T093000Z"] {
+            let property = ical::Property::new("DTSTART", value);
+            assert!(
+                parse_ical_datetime(&property, Tz::UTC).is_err(),
+                "{} should not parse",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn parse_ical_datetime_resolves_dst_transitions() {
+        // Clocks went back an hour at 02:00 on 2024-11-03, so 01:30 happened twice
+        let ambiguous = ical::Property::new_with_attributes(
+            "DTSTART",
+            "20241103T013000",
+            vec![("TZID", "America/New_York")],
+        );
+        assert_eq!(
+            parse_ical_datetime(&ambiguous, Tz::UTC).unwrap(),
+            utc("2024-11-03T05:30:00Z")
+        );
+
+        // Clocks went forward an hour at 02:00 on 2024-03-10, so 02:30 never happened
+        let nonexistent = ical::Property::new_with_attributes(
+            "DTSTART",
+            "20240310T023000",
+            vec![("TZID", "America/New_York")],
+        );
+        assert_eq!(
+            parse_ical_datetime(&nonexistent, Tz::UTC).unwrap(),
+            utc("2024-03-10T07:00:00Z")
+        );
+    }
+
+    #[test]
+    fn oversized_description_is_sanitized_and_folded() {
+        let description: String = "Agenda\u{0}\u{7}\n\tdétails, notes; et cætera "
+            .chars()
+            .cycle()
+            .take(10 * 1024)
+            .collect();
+        let mut original = event("Planning", "2024-03-01T09:00:00Z", "2024-03-01T10:00:00Z");
+        original.description = Some(sanitize_ical_text(&description, 8192));
+
+        let sanitized = original.description.as_deref().unwrap();
+        assert_eq!(sanitized.chars().count(), 8192);
+        assert!(sanitized.ends_with('…'));
+        assert!(!sanitized.contains(['\u{0}', '\u{7}']));
+        assert!(sanitized.contains("\n\tdétails"));
+
+        let serialized = fold_ical_lines(&original.to_ical("uid-1").serialize());
+        for line in serialized.split("\r\n") {
+            assert!(line.len() <= 75, "line of {} octets: {}", line.len(), line);
+        }
+
+        let parsed = minicaldav::parse_ical(&serialized).unwrap();
+        let round_tripped = parse_caldav_event(&parsed.children[0], Tz::UTC).unwrap();
+        assert_eq!(round_tripped.event.description, original.description);
+    }
+
+    #[test]
+    fn parse_caldav_event_defaults_missing_summary() {
+        let data = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:uid-1\r\n\
+                    DTSTART:20240301T090000Z\r\nDTEND:20240301T100000Z\r\n\
+                    END:VEVENT\r\nEND:VCALENDAR\r\n";
+        let parsed = minicaldav::parse_ical(data).unwrap();
+        let event = parse_caldav_event(&parsed.children[0], Tz::UTC).unwrap();
+        assert_eq!(event.caldav_uid, "uid-1");
+        assert_eq!(event.event.summary, "");
+    }
+
+    #[test]
+    fn parse_caldav_event_clamps_reversed_times() {
+        let data = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:uid-1\r\n\
+                    DTSTART:20240301T100000Z\r\nDTEND:20240301T090000Z\r\n\
+                    END:VEVENT\r\nEND:VCALENDAR\r\n";
+        let parsed = minicaldav::parse_ical(data).unwrap();
+        let event = parse_caldav_event(&parsed.children[0], Tz::UTC)
+            .unwrap()
+            .event;
+        assert_eq!(event.start, EventTime::Timed(utc("2024-03-01T10:00:00Z")));
+        assert_eq!(event.end, event.start);
+    }
+
+    #[test]
+    fn clamp_event_end_keeps_all_day_events_a_day_long() {
+        let start = EventTime::AllDay(NaiveDate::from_ymd_opt(2024, 3, 2).unwrap());
+        let end = EventTime::AllDay(NaiveDate::from_ymd_opt(2024, 3, 1).unwrap());
+        assert_eq!(
+            clamp_event_end(start, end, "uid-1"),
+            EventTime::AllDay(NaiveDate::from_ymd_opt(2024, 3, 3).unwrap())
+        );
+        let ordered = EventTime::AllDay(NaiveDate::from_ymd_opt(2024, 3, 3).unwrap());
+        assert_eq!(clamp_event_end(start, ordered, "uid-1"), ordered);
+    }
+
+    #[test]
+    fn parse_caldav_event_takes_first_of_multiple_summaries() {
+        let data = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:uid-1\r\n\
+                    SUMMARY:First\r\nSUMMARY:Second\r\n\
+                    DTSTART:20240301T090000Z\r\nDTEND:20240301T100000Z\r\n\
+                    END:VEVENT\r\nEND:VCALENDAR\r\n";
+        let parsed = minicaldav::parse_ical(data).unwrap();
+        let event = parse_caldav_event(&parsed.children[0], Tz::UTC).unwrap();
+        assert_eq!(event.event.summary, "First");
+    }
+}
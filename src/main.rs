@@ -1,386 +1,225 @@
-use std::{
-    collections::HashSet,
-    hash::{Hash, Hasher},
-    path::Path,
-    time::Duration,
+use gcal_pull_view::{
+    caldav_agent, check_args, google_hub, healthcheck, is_google_auth_error, load_config,
+    lock_pid_file, purge, run_sync, serve_metrics,
 };
-
-use anyhow::Context;
-use chrono::prelude::*;
-use chrono_tz::Tz;
-use google_calendar3::{hyper_rustls, hyper_util, yup_oauth2, CalendarHub};
-use minicaldav::{
-    self,
-    ical::{self, Ical},
-};
-use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use ureq;
-
-fn get_window_radius() -> chrono::TimeDelta {
-    chrono::TimeDelta::days(
-        std::env::var("WINDOW_RADIUS")
-            .unwrap_or_else(|_| "14".to_string())
-            .parse()
-            .unwrap(),
-    )
-}
+use std::time::Duration;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// The longest that syncs are put off while Google keeps refusing our credentials.
+const MAX_AUTH_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+const USAGE: &str = "\
+Mirrors Google calendars into a CalDAV collection.
+
+Usage: gcal-pull-view [OPTIONS]
+
+Options, which take precedence over the environment variables named:
+      --caldav-uri <URI>          CalDAV collection to sync into (CALDAV_URI)
+      --google-calendar-id <ID>   Google calendar to sync from (GOOGLE_CALENDAR_ID)
+      --window-radius <DAYS>      Days either side of now to sync (WINDOW_RADIUS)
+      --interval <SECONDS>        Time between syncs (SYNC_INTERVAL)
+      --config <PATH>             TOML config file to read settings from (CONFIG_FILE)
+      --once                      Sync once and exit (RUN_ONCE)
+      --dry-run                   Log the changes a sync would make without making them (DRY_RUN)
+      --diff-only                 Exit with 2 if a sync would make changes (DIFF_ONLY)
+      --check                     Check Google and CalDAV can be reached, then exit (HEALTHCHECK)
+      --purge                     Delete every event we mirrored, then exit (PURGE)
+      --yes                       Don't ask before purging
+  -v, --verbose                   Log more; repeat for even more (VERBOSE)
+  -h, --help                      Print this help
+";
+
+async fn shutdown_signal() {
+    let interrupt = tokio::signal::ctrl_c();
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-fn get_caldav_uri() -> String {
-    std::env::var("CALDAV_URI").unwrap()
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
 }
 
-fn get_google_calendar_id() -> String {
-    std::env::var("GOOGLE_CALENDAR_ID").unwrap()
-}
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = match check_args(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprint!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.has("--help") || args.has("-h") {
+        print!("{}", USAGE);
+        return Ok(());
+    }
 
-fn get_google_calendar_secrets_dir() -> String {
-    std::env::var("GOOGLE_CALENDAR_SECRETS_DIR").unwrap_or_else(|_| ".".to_string())
-}
+    // Per-event changes are logged at debug level, so by default only the sync summary shows
+    let default_filter = match args.verbosity(std::env::var("VERBOSE").ok().as_deref()) {
+        0 => "info",
+        1 => "info,gcal_pull_view=debug",
+        _ => "info,gcal_pull_view=trace",
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_filter)),
+        )
+        .init();
 
-#[derive(Debug)]
-struct Event {
-    start: DateTime<Utc>,
-    end: DateTime<Utc>,
-    summary: String,
-}
+    let config = load_config(args)?;
 
-impl PartialEq for Event {
-    fn eq(&self, other: &Self) -> bool {
-        self.start == other.start && self.end == other.end && self.summary == other.summary
+    if config.healthcheck {
+        return healthcheck(&config).await;
     }
-}
 
-impl Eq for Event {}
+    // Two instances syncing the same calendar would race to create and delete the same events
+    let _pid_file = match &config.pid_file {
+        Some(path) => Some(lock_pid_file(path)?),
+        None => None,
+    };
 
-impl Hash for Event {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.start.hash(state);
-        self.end.hash(state);
-        self.summary.hash(state);
+    if config.purge {
+        return purge(&config).await;
     }
-}
 
-#[derive(Debug)]
-struct EventWithCaldavUid {
-    caldav_uid: String,
-    event: Event,
-}
+    let hub = google_hub(&config).await?;
+    let agent = caldav_agent(&config)?;
 
-impl Event {
-    fn to_ical(&self, uid: &str) -> Ical {
-        let mut vcalendar = Ical::new("VCALENDAR".to_string());
-        let mut vevent = Ical::new("VEVENT".to_string());
-        vevent.properties.push(ical::Property::new("UID", uid));
-        vevent
-            .properties
-            .push(ical::Property::new("SUMMARY", &self.summary));
-        vevent.properties.push(ical::Property::new(
-            "DTSTART",
-            &self.start.format("%Y%m%dT%H%M%SZ").to_string(),
-        ));
-        vevent.properties.push(ical::Property::new(
-            "DTEND",
-            &self.end.format("%Y%m%dT%H%M%SZ").to_string(),
-        ));
-        vcalendar.children.push(vevent);
-        vcalendar
+    // For monitoring whether the mirror is in sync. Exits with 2 when changes are needed, leaving
+    // 1 for failures.
+    if config.diff_only {
+        let report = run_sync(&config, &hub, &agent).await?;
+        if !report.planned.is_empty() {
+            std::process::exit(2);
+        }
+        return Ok(());
     }
-}
 
-fn parse_ical_datetime(property: &ical::Property) -> anyhow::Result<DateTime<Utc>> {
-    let str = property.value.as_str();
-    if str.ends_with('Z') {
-        Ok(NaiveDateTime::parse_from_str(property.value.as_str(), "%Y%m%dT%H%M%SZ")?.and_utc())
-    } else {
-        let tz: Tz = property
-            .attributes
-            .get("TZID")
-            .with_context(|| "Missing key TZID in ical datetime property")?
-            .parse()?;
-        Ok(
-            NaiveDateTime::parse_from_str(property.value.as_str(), "%Y%m%dT%H%M%S")?
-                .and_local_timezone(tz)
-                .single()
-                .with_context(|| "Ambiguous or invalid local time")?
-                .to_utc(),
-        )
+    // For use under an external scheduler; the exit status reports whether the sync succeeded
+    if config.run_once {
+        let report = run_sync(&config, &hub, &agent).await?;
+        info!("{}", report);
+        return report.ensure_ok();
     }
-}
-
-fn get_ical_property<'a>(
-    ical: &'a Ical,
-    property_name: &str,
-) -> anyhow::Result<&'a ical::Property> {
-    Ok(ical
-        .properties
-        .iter()
-        .find(|p| p.name == property_name)
-        .with_context(|| format!("Looking up property: {}", property_name))?)
-}
 
-fn describe_ical_event(event: &Ical) -> String {
-    format!(
-        "{} '{}' at {}",
-        event.name,
-        event
-            .properties
-            .iter()
-            .find(|p| p.name == "SUMMARY")
-            .map(|p| p.value.as_ref())
-            .unwrap_or("(Unknown summary)"),
-        event
-            .properties
-            .iter()
-            .find(|p| p.name == "DTSTART")
-            .map(|p| p.value.as_ref())
-            .unwrap_or("(Unknown start time)")
-    )
-}
-
-fn describe_event(event: &Event) -> String {
-    format!("'{}' at {}", event.summary, event.start)
-}
-
-async fn fetch_caldav_events(
-    agent: &ureq::Agent,
-    caldav_url: &str,
-) -> anyhow::Result<Vec<EventWithCaldavUid>> {
-    let data = agent.get(caldav_url).call()?.into_string()?;
-    let events = minicaldav::parse_ical(&data)?;
-    Ok(events
-        .children
-        .iter()
-        .filter(|item| item.name.as_str() == "VEVENT")
-        .filter(
-            |ical_event| match get_ical_property(ical_event, "DTSTART") {
-                // We only want events that have a time component
-                Ok(prop) => prop.value.as_str().contains("T"),
-                Err(_) => false,
-            },
-        )
-        .map(|ical_event| {
-            (|| {
-                Ok::<EventWithCaldavUid, anyhow::Error>(EventWithCaldavUid {
-                    caldav_uid: get_ical_property(ical_event, "UID")?.value.clone(),
-                    event: Event {
-                        start: parse_ical_datetime(get_ical_property(ical_event, "DTSTART")?)?,
-                        end: parse_ical_datetime(get_ical_property(ical_event, "DTEND")?)?,
-                        summary: get_ical_property(ical_event, "SUMMARY")?.value.clone(),
-                    },
-                })
-            })()
-            .with_context(|| {
-                format!(
-                    "Failed processing iCal event ({})",
-                    describe_ical_event(ical_event)
-                )
-            })
-        })
-        .filter_map(|result: anyhow::Result<EventWithCaldavUid>| {
-            if let Err(e) = &result {
-                eprintln!("Skipping event: {:#}", e);
+    if let Some(metrics_addr) = config.metrics_addr.clone() {
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(&metrics_addr).await {
+                error!("Metrics server stopped: {:#}", e);
             }
-            result.ok()
-        })
-        .collect())
-}
+        });
+    }
 
-fn is_not_accepted(google_event: &google_calendar3::api::Event) -> bool {
-    google_event.attendees.iter().flatten().any(|attendee| {
-        match attendee.response_status.as_ref() {
-            Some(status) if status == "declined" => true,
-            Some(status) if status == "needsAction" => true,
-            _ => false,
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    // Spreads out the first syncs of instances started together, as in a deploy. Not applied with
+    // --once, where the external scheduler decides the timing.
+    let startup_delay = config
+        .sync_interval
+        .mul_f64(thread_rng().gen_range(0.0..1.0));
+    info!("Waiting {:?} before the first sync", startup_delay);
+    tokio::select! {
+        _ = tokio::time::sleep(startup_delay) => {}
+        _ = &mut shutdown => {
+            info!("Shutting down");
+            return Ok(());
         }
-    })
-}
-
-const PASSIVE_EVENTS: [&str; 4] = ["Color Coordinated", "All Hands", "Async Eng", "TCIF"];
-
-fn is_passive_event(google_event: &google_calendar3::api::Event) -> bool {
-    let summary = match google_event.summary.as_ref() {
-        Some(summary) => summary,
-        None => return false,
-    };
-
-    PASSIVE_EVENTS.iter().any(|event| summary.contains(event))
-}
-
-async fn fetch_google_events() -> anyhow::Result<Vec<Event>> {
-    let now = chrono::Utc::now();
-    let client = hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
-        .build(
-            hyper_rustls::HttpsConnectorBuilder::new()
-                .with_native_roots()
-                .unwrap()
-                .https_or_http()
-                .enable_http1()
-                .build(),
-        );
-
-    let secrets_dir = get_google_calendar_secrets_dir();
-
-    let secret: yup_oauth2::ApplicationSecret =
-        yup_oauth2::read_application_secret(Path::new(&secrets_dir).join("secret.json"))
-            .await
-            .unwrap();
-
-    let auth = yup_oauth2::InstalledFlowAuthenticator::builder(
-        secret,
-        yup_oauth2::InstalledFlowReturnMethod::HTTPRedirect,
-    )
-    .persist_tokens_to_disk(Path::new(&secrets_dir).join("tokens.json"))
-    .build()
-    .await
-    .unwrap();
-    let hub = CalendarHub::new(client.clone(), auth);
-    let window_radius = get_window_radius();
+    }
 
-    let result = hub
-        .events()
-        .list(&get_google_calendar_id())
-        .add_event_types("default")
-        .max_results(2500)
-        .single_events(true)
-        .order_by("startTime")
-        .max_attendees(1)
-        .time_min(now - window_radius)
-        .time_max(now + window_radius)
-        .doit()
-        .await?
-        .1;
+    let mut interval = tokio::time::interval(config.sync_interval);
+    interval.set_missed_tick_behavior(config.missed_tick_behavior);
+    let max_consecutive_failures = config.max_consecutive_failures;
+    let mut consecutive_failures = 0;
+    let mut auth_failures = 0;
 
-    let events = result
-        .items
-        .with_context(|| "Calendar events should exist")?
-        .iter()
-        .filter_map(|google_event| {
-            if is_not_accepted(google_event) {
-                return None;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                return Ok(());
             }
+        }
 
-            if is_passive_event(google_event) {
-                return None;
+        // A shutdown signal received mid-sync only takes effect once the sync is done, so that we
+        // never stop partway through writing to CalDAV
+        let mut shutdown_requested = false;
+        let result = {
+            let sync = async {
+                let report = run_sync(&config, &hub, &agent).await?;
+                info!("{}", report);
+                report.ensure_ok()
+            };
+            tokio::pin!(sync);
+            tokio::select! {
+                result = &mut sync => result,
+                _ = &mut shutdown => {
+                    info!("Shutting down after current sync");
+                    shutdown_requested = true;
+                    sync.await
+                }
             }
+        };
 
-            if google_event.summary.as_ref()?.contains("Product Design") {
-                println!("{:#?}", google_event);
+        let mut backoff = None;
+        match result {
+            Ok(()) => {
+                consecutive_failures = 0;
+                auth_failures = 0;
+            }
+            Err(e) => {
+                consecutive_failures += 1;
+                error!(
+                    "Sync failed ({}/{} consecutive failures): {:#}",
+                    consecutive_failures, max_consecutive_failures, e
+                );
+                if consecutive_failures >= max_consecutive_failures {
+                    return Err(e.context("Too many consecutive sync failures"));
+                }
+                // Unlike network trouble, this won't clear up by itself, so there's no sense
+                // asking Google again every interval
+                if is_google_auth_error(&e) {
+                    auth_failures += 1;
+                    let delay = config
+                        .sync_interval
+                        .saturating_mul(2u32.saturating_pow(auth_failures))
+                        .min(MAX_AUTH_BACKOFF);
+                    warn!(
+                        "Google refused our credentials, so re-authentication is required: with \
+                         installed app auth, delete {} and run interactively to authorize again. \
+                         Retrying in {:?}",
+                        config.google_tokens_path.display(),
+                        delay
+                    );
+                    backoff = Some(delay);
+                }
             }
-
-            Some(Event {
-                start: google_event.start.as_ref()?.date_time?,
-                end: google_event.end.as_ref()?.date_time?,
-                summary: google_event.summary.as_ref()?.clone(),
-            })
-        })
-        .collect();
-
-    Ok(events)
-}
-
-fn find_diff<'a>(
-    current: &'a [EventWithCaldavUid],
-    target: &'a [Event],
-) -> (Vec<&'a EventWithCaldavUid>, Vec<&'a Event>) {
-    let current_set: HashSet<&Event> = current.iter().map(|e| &e.event).collect();
-    let target_set: HashSet<&Event> = target.iter().collect();
-
-    let mut to_delete = Vec::new();
-    let mut to_create = Vec::new();
-
-    for event_with_caldav_uid in current {
-        if !target_set.contains(&event_with_caldav_uid.event) {
-            to_delete.push(event_with_caldav_uid);
         }
-    }
 
-    for event in target {
-        if !current_set.contains(event) {
-            to_create.push(event);
+        if shutdown_requested {
+            return Ok(());
         }
-    }
-
-    (to_delete, to_create)
-}
-
-async fn create_caldav_event(
-    agent: &ureq::Agent,
-    caldav_url: &str,
-    event: &Event,
-) -> anyhow::Result<()> {
-    let random_uid: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(24)
-        .map(char::from)
-        .collect();
-    let uri = format!("{}{}.ics", caldav_url, random_uid);
-    println!("Creating event {} at {}", describe_event(event), uri);
-
-    agent
-        .put(&uri)
-        .send_string(&event.to_ical(&random_uid).serialize())
-        .with_context(|| format!("Failed to create event {}", describe_event(event)))?;
-
-    Ok(())
-}
-
-async fn delete_caldav_event(
-    agent: &ureq::Agent,
-    caldav_url: &str,
-    caldav_event: &EventWithCaldavUid,
-) -> anyhow::Result<()> {
-    let uri = format!("{}{}.ics", caldav_url, caldav_event.caldav_uid);
-    println!(
-        "Deleting event {} at {}",
-        describe_event(&caldav_event.event),
-        uri
-    );
 
-    agent.delete(&uri).call().with_context(|| {
-        format!(
-            "Failed to delete event {}",
-            describe_event(&caldav_event.event)
-        )
-    })?;
-
-    Ok(())
-}
-
-async fn sync() -> anyhow::Result<()> {
-    let now = chrono::Utc::now();
-    println!("Starting sync at {}", now);
-
-    let agent = ureq::Agent::new();
-    let caldav_url = get_caldav_uri();
-
-    let caldav_events = fetch_caldav_events(&agent, &caldav_url).await?;
-    let google_events = fetch_google_events().await?;
-    let (to_delete, to_create) = find_diff(&caldav_events, &google_events);
-
-    println!(
-        "{} events to delete, {} events to create",
-        to_delete.len(),
-        to_create.len()
-    );
-
-    for event in to_delete {
-        delete_caldav_event(&agent, &caldav_url, event).await?;
-    }
-
-    for event in to_create {
-        create_caldav_event(&agent, &caldav_url, event).await?;
-    }
-
-    Ok(())
-}
-
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let mut interval = tokio::time::interval(Duration::from_secs(60));
-
-    loop {
-        interval.tick().await;
-        sync().await?;
+        if let Some(backoff) = backoff {
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = &mut shutdown => {
+                    info!("Shutting down");
+                    return Ok(());
+                }
+            }
+        }
     }
 }
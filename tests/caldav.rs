@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use chrono::prelude::*;
+use gcal_pull_view::{
+    create_caldav_event, delete_caldav_event, fetch_caldav_events, load_config_from, Args, Event,
+    EventTime,
+};
+
+#[derive(Debug)]
+struct RecordedRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl RecordedRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+const FIXTURE_MULTISTATUS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+  <d:response>
+    <d:href>/cal/existing-resource.ics</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:getetag>"etag-1"</d:getetag>
+        <c:calendar-data>BEGIN:VCALENDAR
+VERSION:2.0
+BEGIN:VEVENT
+UID:existing-uid
+X-GCAL-PULL-VIEW:1
+X-GCAL-ID:google-1
+X-GCAL-CALENDAR-ID:primary
+SUMMARY:Existing\, event
+DTSTART:20240301T090000Z
+DTEND:20240301T100000Z
+END:VEVENT
+END:VCALENDAR
+</c:calendar-data>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+/// Starts a minimal CalDAV server on a local port, which answers REPORTs with a fixed calendar and
+/// accepts any PUT or DELETE, recording every request it gets.
+fn start_mock_server() -> (String, Arc<Mutex<Vec<RecordedRequest>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}/cal/", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+
+    let recorded = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap_or_default().to_string();
+            let path = parts.next().unwrap_or_default().to_string();
+
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    headers.push((name.trim().to_string(), value.trim().to_string()));
+                }
+            }
+            let content_length = headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+                .map_or(0, |(_, value)| value.parse().unwrap());
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let (status, response_body) = match method.as_str() {
+                "REPORT" => ("207 Multi-Status", FIXTURE_MULTISTATUS),
+                "PUT" => ("201 Created", ""),
+                "DELETE" => ("204 No Content", ""),
+                _ => ("405 Method Not Allowed", ""),
+            };
+            // Recorded before responding, so the client never gets ahead of the record
+            recorded.lock().unwrap().push(RecordedRequest {
+                method,
+                path,
+                headers,
+                body: String::from_utf8(body).unwrap(),
+            });
+
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                response_body.len(),
+                response_body
+            )
+            .unwrap();
+        }
+    });
+
+    (base_url, requests)
+}
+
+#[tokio::test]
+async fn fetches_creates_and_deletes_against_caldav_server() {
+    let (base_url, requests) = start_mock_server();
+    // Only what's given here, so that the runner's own environment can't change the requests
+    let env = HashMap::from([
+        ("CALDAV_URI".to_string(), base_url.clone()),
+        ("GOOGLE_CALENDAR_ID".to_string(), "primary".to_string()),
+    ]);
+    let config = load_config_from(Args::default(), env).unwrap();
+    let agent = ureq::Agent::new();
+    let now = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+
+    let caldav_events = fetch_caldav_events(&config, &agent, &base_url, now)
+        .await
+        .unwrap();
+    assert_eq!(caldav_events.len(), 1);
+    let existing = &caldav_events[0];
+    assert_eq!(existing.caldav_uid, "existing-uid");
+    assert_eq!(existing.etag.as_deref(), Some("\"etag-1\""));
+    assert_eq!(existing.event.summary, "Existing, event");
+    assert_eq!(existing.event.google_id.as_deref(), Some("google-1"));
+
+    let event = Event {
+        google_id: Some("google-2".to_string()),
+        calendar_id: Some("primary".to_string()),
+        start: EventTime::Timed(Utc.with_ymd_and_hms(2024, 3, 2, 9, 0, 0).unwrap()),
+        end: EventTime::Timed(Utc.with_ymd_and_hms(2024, 3, 2, 10, 0, 0).unwrap()),
+        summary: "New; event".to_string(),
+        description: None,
+        location: None,
+        rrule: None,
+        exdates: Vec::new(),
+        rdates: Vec::new(),
+        transparent: false,
+        organizer: None,
+        organizer_name: None,
+        color: None,
+        time_zone: None,
+        attendee_count: None,
+        private: false,
+        created: None,
+        last_modified: None,
+    };
+    create_caldav_event(&config, &agent, &base_url, &event, None)
+        .await
+        .unwrap();
+    delete_caldav_event(&config, &agent, &base_url, existing, None)
+        .await
+        .unwrap();
+
+    let requests = requests.lock().unwrap();
+    let methods: Vec<&str> = requests.iter().map(|r| r.method.as_str()).collect();
+    assert_eq!(methods, vec!["REPORT", "PUT", "DELETE"]);
+
+    let report = &requests[0];
+    assert_eq!(report.path, "/cal/");
+    assert_eq!(report.header("Depth"), Some("1"));
+    assert!(report.body.contains("<c:calendar-query"));
+    assert!(report
+        .body
+        .contains(r#"start="20240216T000000Z" end="20240315T000000Z""#));
+
+    let put = &requests[1];
+    assert!(put.path.starts_with("/cal/") && put.path.ends_with(".ics"));
+    assert_eq!(
+        put.header("Content-Type"),
+        Some("text/calendar; charset=utf-8")
+    );
+    let uid = put.path["/cal/".len()..put.path.len() - ".ics".len()].to_string();
+    for line in [
+        "BEGIN:VCALENDAR",
+        "VERSION:2.0",
+        &format!("UID:{}", uid),
+        "X-GCAL-PULL-VIEW:1",
+        "X-GCAL-ID:google-2",
+        r"SUMMARY:New\; event",
+        "DTSTART:20240302T090000Z",
+        "DTEND:20240302T100000Z",
+    ] {
+        assert!(
+            put.body.split("\r\n").any(|l| l == line),
+            "{} missing from {}",
+            line,
+            put.body
+        );
+    }
+
+    let delete = &requests[2];
+    assert_eq!(delete.path, "/cal/existing-resource.ics");
+    assert_eq!(delete.header("If-Match"), Some("\"etag-1\""));
+}